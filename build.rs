@@ -31,6 +31,8 @@ fn main() {
         .file("runtime/src/kernel/core.cpp")
         .file("runtime/src/datastore/dspackethandler.cpp")
         .file("runtime/src/datastore/utils.cpp")
+        // Defaults of interfaces the runtime may not implement.
+        .file("src/external/ffi_defaults.cpp")
         // VNF app files.
         .file("runtime/vnf/SL/sl.cpp")
         // .shared_flag(true)
@@ -38,5 +40,6 @@ fn main() {
         .compile("morph-db");
 
     println!("cargo:rerun-if-changed=include/ffi.h");
+    println!("cargo:rerun-if-changed=src/external/ffi_defaults.cpp");
     println!("cargo:rerun-if-changed=runtime");
 }
\ No newline at end of file
@@ -9,4 +9,6 @@ extern "C++" {
     void VNFThread(int32_t c, rust::Vec<rust::String> v);
     rust::Vec<uint8_t> execute_sa_udf(uint64_t txnReqId_jni, int saIdx, rust::Vec<uint8_t> value, int param_count);
     int32_t txn_finished(uint64_t txnReqId_jni);
+    // Outcome with the abort code, 0 if committed. Defaults to txn_finished in src/external/ffi_defaults.cpp.
+    int32_t txn_finished_with_reason(uint64_t txnReqId_jni, uint32_t abort_code, rust::String abort_message);
 }
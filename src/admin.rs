@@ -0,0 +1,139 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::database::simpledb::DB;
+use crate::monitor::monitor;
+use crate::tpg::dot::{self, DotFilter};
+use crate::tpg::{tpg::TPG, txn_node::{TxnStatus, WAITING_TXNS}};
+use crate::utils;
+use crate::worker::construct_thread::{GRACEFUL_SHUTDOWN, INGRESS_PAUSED};
+
+/*
+    Admin commands on a Unix socket at CONFIG.admin_socket, to inspect a running engine. Use the db4nfv-admin client.
+    One command per connection, as a line. The reply follows till the connection closes, and starts with "ERR " on failure.
+    - config: the config in JSON.
+    - counters: counters of each thread and gauges, as lines of cnt.csv.
+    - versions <state> [table]: versions held of a state, e.g. "versions balance_3". Table defaults to "default".
+    - tpg [key <state>] [txn <req_id>]: the live TPG in DOT. See tpg::dot.
+    - checkpoint: newest value of each state, written to checkpoint-<ns>.csv under log_dir.
+      Refused unless ingress is paused and every transaction finished, as versions not committed would be written too.
+    - pause / resume: stop or restart taking new transactions. Those in the TPG still run.
+    - verbosity <on|off>: sets CONFIG.verbose, which gates the [DEBUG] prints. Nothing logs through the log crate.
+    Connections are served one by one on this thread.
+ */
+pub fn admin_thread() {
+    let path = match CONFIG.read().unwrap().admin_socket.clone() {
+        Some(path) => path,
+        None => return,
+    };
+    // Left by an earlier run.
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Failed to bind admin socket on {}: {}", path, err);
+            return;
+        }
+    };
+    // Polled, to notice shutdown.
+    listener.set_nonblocking(true).expect("Failed to set admin listener nonblocking.");
+    loop {
+        if unsafe { GRACEFUL_SHUTDOWN } {
+            println!("Admin socket shutdown. ");
+            break;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(err) = serve(stream) {
+                    eprintln!("Failed to serve admin command: {}", err);
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+            Err(err) => eprintln!("Failed to accept admin connection: {}", err),
+        }
+    }
+    let _ = fs::remove_file(&path);
+}
+
+fn serve(stream: UnixStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let reply = match execute(&args) {
+        Ok(reply) => reply,
+        Err(err) => format!("ERR {}\n", err),
+    };
+    let mut stream = &stream;
+    stream.write_all(reply.as_bytes())?;
+    stream.flush()
+}
+
+fn execute(args: &[&str]) -> Result<String, String> {
+    match args {
+        ["config"] => serde_json::to_string_pretty(&*CONFIG.read().unwrap())
+            .map(|c| c + "\n")
+            .map_err(|e| e.to_string()),
+        ["counters"] => Ok(monitor::counters_csv()),
+        ["versions", state] | ["versions", state, _] => {
+            let table = args.get(2).copied().unwrap_or("default");
+            let versions = DB.get().ok_or("database not ready")?
+                .versions(table, state)
+                .ok_or_else(|| format!("no state {} in table {}", state, table))?;
+            let mut out = String::from("ts,written,merge,value\n");
+            versions.iter().for_each(|v| {
+                out += &format!("{},{},{},{:?}\n",
+                    v.ts, v.written, v.merge.map_or(String::from("-"), |op| format!("{:?}", op)), v.value);
+            });
+            Ok(out)
+        },
+        ["tpg", filter @ ..] => {
+            let mut f = DotFilter::default();
+            for pair in filter.chunks(2) {
+                match pair {
+                    ["key", key] => f.key = Some(key.to_string()),
+                    ["txn", req_id] => f.txn = Some(req_id.parse().map_err(|_| format!("bad txn id {}", req_id))?),
+                    _ => return Err(String::from("usage: tpg [key <state>] [txn <req_id>]")),
+                }
+            }
+            Ok(dot::render(&f))
+        },
+        ["checkpoint"] => {
+            if !INGRESS_PAUSED.load(Ordering::Relaxed) {
+                return Err(String::from("ingress not paused. Run pause first."))
+            }
+            // Aborted ones roll back till they finish.
+            let unfinished = WAITING_TXNS.load() as usize + TPG.get().map_or(0, |tpg| {
+                tpg.live_txns().iter().filter(|tn| tn.status.load() == TxnStatus::ABORTED).count()
+            });
+            if unfinished != 0 {
+                return Err(format!("{} transactions not finished. Retry once the TPG drained.", unfinished))
+            }
+            let path = format!("{}/checkpoint-{}.csv", CONFIG.read().unwrap().log_dir, utils::current_time_ns());
+            let states = DB.get().ok_or("database not ready")?
+                .checkpoint(&path)
+                .map_err(|e| format!("failed to write {}: {}", path, e))?;
+            Ok(format!("{} states written to {}\n", states, path))
+        },
+        ["pause"] => {
+            INGRESS_PAUSED.store(true, Ordering::Relaxed);
+            Ok(String::from("ingress paused\n"))
+        },
+        ["resume"] => {
+            INGRESS_PAUSED.store(false, Ordering::Relaxed);
+            Ok(String::from("ingress resumed\n"))
+        },
+        ["verbosity", switch @ ("on" | "off")] => {
+            CONFIG.write().unwrap().verbose = *switch == "on";
+            Ok(format!("verbose {}\n", switch))
+        },
+        ["verbosity", ..] => Err(String::from("usage: verbosity on|off")),
+        _ => Err(String::from("unknown command. Try config, counters, versions, tpg, checkpoint, pause, resume or verbosity.")),
+    }
+}
@@ -0,0 +1,37 @@
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+/*
+    Client of the admin socket. Sends one command and prints the reply.
+    Usage: db4nfv-admin <socket> <command> [args...], e.g. `db4nfv-admin /tmp/db4nfv.sock tpg txn 42 > tpg.dot`.
+    See admin in the engine for commands.
+ */
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <socket> <command> [args...]", args[0]);
+        eprintln!("Commands: config, counters, versions <state> [table], tpg [key <state>] [txn <req_id>], checkpoint, pause, resume, verbosity <on|off>");
+        process::exit(2);
+    }
+    let mut stream = match UnixStream::connect(&args[1]) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {}", args[1], err);
+            process::exit(1);
+        }
+    };
+    let mut reply = String::new();
+    let result = writeln!(stream, "{}", args[2..].join(" "))
+        .and_then(|_| stream.read_to_string(&mut reply));
+    if let Err(err) = result {
+        eprintln!("Failed to talk to {}: {}", args[1], err);
+        process::exit(1);
+    }
+    if let Some(err) = reply.strip_prefix("ERR ") {
+        eprint!("{}", err);
+        process::exit(1);
+    }
+    print!("{}", reply);
+}
@@ -8,6 +8,10 @@ use std::path::PathBuf;
 use lazy_static::lazy_static;
 use std::sync::RwLock;
 
+use crate::tpg::scheduling::PolicyKind;
+use crate::worker::idle::IdleStrategy;
+use crate::topology::CoreMap;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     // Define VNF thread nums.
@@ -34,8 +38,79 @@ pub struct Config {
     pub monitor_enabled: bool,
     // Monitor logging path. Default to be current path.
     pub log_dir: String,
+    // One in trace_sample_n transactions is traced in history.csv. 1 to trace all, 0 for none. Needs monitor_enabled.
+    #[serde(default = "default_trace_sample_n")]
+    pub trace_sample_n: u64,
+    // Sample by hash of request ids instead of by count, to trace the same transactions across runs.
+    #[serde(default)]
+    pub trace_sample_by_hash: bool,
+    // Also write traced records to trace.json in Chrome trace format. See monitor::chrome_trace.
+    #[serde(default)]
+    pub trace_export: bool,
+    // Dump the TPG in DOT to log_dir when any thread panics, e.g. on failed assertions. See tpg::dot.
+    #[serde(default)]
+    pub dump_tpg_on_panic: bool,
+    // Period of the watchdog looking for stuck transactions. 0 to disable. See worker::watchdog.
+    #[serde(default)]
+    pub watchdog_interval_ms: u64,
+    // Transactions waiting longer than this since linked are reported stuck.
+    #[serde(default = "default_watchdog_stuck_ms")]
+    pub watchdog_stuck_ms: u64,
+    // Abort stuck transactions, after reporting.
+    #[serde(default)]
+    pub watchdog_abort: bool,
+    // History records buffered for each thread till the monitor writes them. Records beyond are dropped.
+    #[serde(default = "default_monitor_buffer_size")]
+    pub monitor_buffer_size: usize,
+    // Accepted events reset by one abortion before its states turn pessimistic. 0 to never switch.
+    #[serde(default)]
+    pub cascade_threshold: usize,
+    // Commits writing a pessimistic state, with no abortion cascading over the threshold, before it turns optimistic again. 0 to stay pessimistic.
+    #[serde(default = "default_pessimistic_commits")]
+    pub pessimistic_commits: usize,
+    // Priority classes of the ready queue. Priority of transactions above are served as the highest.
+    #[serde(default = "default_priority_levels")]
+    pub priority_levels: u8,
+    // Events served by priority in a row before one served round robin. 0 to serve by priority only.
+    #[serde(default = "default_priority_starvation_limit")]
+    pub priority_starvation_limit: usize,
+    // How executor threads choose the next event. See tpg::scheduling.
+    #[serde(default = "default_scheduling_policy")]
+    pub scheduling_policy: PolicyKind,
+    // Partition states by key to executor threads. Overrides scheduling_policy with key affinity, and disables stealing.
+    #[serde(default)]
+    pub partitioned: bool,
+    // What worker and construct threads do when idle. See worker::idle.
+    #[serde(default = "default_idle_strategy")]
+    pub idle_strategy: IdleStrategy,
+    // Polls before yielding or parking.
+    #[serde(default = "default_idle_spin_limit")]
+    pub idle_spin_limit: u32,
+    // Longest park. Bounds the delay of a missed wakeup.
+    #[serde(default = "default_idle_park_timeout_us")]
+    pub idle_park_timeout_us: u64,
+    // Cores of each thread role. See topology.
+    #[serde(default)]
+    pub core_map: CoreMap,
+    // Address serving metrics in Prometheus text format, e.g. "127.0.0.1:9184". None to disable.
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    // Path of the Unix socket for admin commands, e.g. "/tmp/db4nfv.sock". None to disable. See admin.
+    #[serde(default)]
+    pub admin_socket: Option<String>,
 }
 
+fn default_watchdog_stuck_ms() -> u64 { 1000 }
+fn default_trace_sample_n() -> u64 { 1 }
+fn default_monitor_buffer_size() -> usize { 1 << 16 }
+fn default_pessimistic_commits() -> usize { 1000 }
+fn default_priority_levels() -> u8 { 4 }
+fn default_priority_starvation_limit() -> usize { 16 }
+fn default_scheduling_policy() -> PolicyKind { PolicyKind::TimestampFirst }
+fn default_idle_strategy() -> IdleStrategy { IdleStrategy::Spin }
+fn default_idle_spin_limit() -> u32 { 1000 }
+fn default_idle_park_timeout_us() -> u64 { 1000 }
+
 lazy_static! {
     pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
 }
@@ -55,6 +130,26 @@ impl Default for Config {
             monitor_enabled: true,
             max_event_batch: 10,
             log_dir: String::from("./perf"),
+            trace_sample_n: default_trace_sample_n(),
+            trace_sample_by_hash: false,
+            trace_export: false,
+            dump_tpg_on_panic: false,
+            watchdog_interval_ms: 0,
+            watchdog_stuck_ms: default_watchdog_stuck_ms(),
+            watchdog_abort: false,
+            monitor_buffer_size: default_monitor_buffer_size(),
+            cascade_threshold: 0,
+            pessimistic_commits: default_pessimistic_commits(),
+            priority_levels: default_priority_levels(),
+            priority_starvation_limit: default_priority_starvation_limit(),
+            scheduling_policy: default_scheduling_policy(),
+            partitioned: false,
+            idle_strategy: default_idle_strategy(),
+            idle_spin_limit: default_idle_spin_limit(),
+            idle_park_timeout_us: default_idle_park_timeout_us(),
+            core_map: CoreMap::default(),
+            metrics_addr: None,
+            admin_socket: None,
         }
     }
 }
@@ -1,3 +1,5 @@
+use crate::ds::events::MergeOp;
+
 pub trait Database {
 	// Init.
 	fn new() -> Self;
@@ -7,6 +9,8 @@ pub trait Database {
 	fn reset_version(&self, table: &str, key: &str, ts: u64); // Debug api. Could just remove.
 	fn write_version(&self, table: &str, key: &str, ts: u64, value: &Vec<u8>); // At certain version.
 	fn push_version(&self, table: &str, key: &str, ts: u64, value: &Vec<u8>); // Be sure to insert at certain result.
+	fn reserve_version(&self, table: &str, key: &str, ts: u64); // Empty slot at the end. Written later in any order.
+	fn write_delta(&self, table: &str, key: &str, ts: u64, value: &[u8], op: MergeOp); // Commutative delta. Resolved on read.
 	fn copy_last_version(&self, table: &str, key: &str, ts: u64, has_slot: bool);
 	fn release_version(&self, table: &str, key: &str, ts: u64);
 
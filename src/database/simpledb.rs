@@ -1,9 +1,16 @@
 use once_cell::sync::OnceCell;
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::fmt::{Debug, Write as _};
+use std::fs;
+use std::io;
 use crate::config::CONFIG;
 use crate::database::api;
+use crate::ds::events::MergeOp;
 use crate::ds::ringbuf::{self, RingBuf};
+use crate::tpg::scheduling::{self, PolicyKind};
+use crate::topology;
+use std::thread;
+use crossbeam::atomic::AtomicCell;
 
 pub(crate) static DB: OnceCell<SimpleDB> = OnceCell::new();
 
@@ -37,6 +44,14 @@ impl api::Database for SimpleDB {
 		self.tables[table].copy_last_version(key, ts, has_slot);	
     }
 
+	fn reserve_version(&self, table: &str, key: &str, ts: u64) {
+		self.tables[table].reserve_version(key, ts);	
+    }
+
+	fn write_delta(&self, table: &str, key: &str, ts: u64, value: &[u8], op: MergeOp) {
+		self.tables[table].write_delta(key, ts, value, op);	
+    }
+
 	fn release_version(&self, table: &str, key: &str, ts: u64) {
 		self.tables[table].release_version(key, ts);	
     }
@@ -47,10 +62,137 @@ impl api::Database for SimpleDB {
 
 }
 
+// Version store health, over all states.
+#[derive(Debug, Default, Clone)]
+pub struct VersionStats {
+	pub versions: usize,
+	pub capacity: usize,
+	// States holding 0, 1, 2-3, 4-7 versions and so on, by bucket. See bucket_of.
+	pub per_key: Vec<usize>,
+	// Times a ringbuf got full.
+	pub full_events: u64,
+}
+
+// Bucket of a count of versions. 0, 1, 2-3, 4-7 and so on.
+pub fn bucket_of(versions: usize) -> usize {
+	versions.checked_ilog2().map_or(0, |b| b as usize + 1)
+}
+
+/*
+	Versions held by each state of a table, kept as versions are pushed and released, so stats need no scan.
+	Counts are taken after the change, so one racing with another on the same state may be late till the next change.
+ */
+struct VersionCounts {
+	// Versions of each state when counted last.
+	held: Vec<AtomicCell<usize>>,
+	// States in each bucket of held.
+	buckets: Vec<AtomicCell<i64>>,
+	total: AtomicCell<i64>,
+	full_events: AtomicCell<u64>,
+}
+
+impl VersionCounts {
+	fn new(states: usize, cap: usize) -> Self {
+		let buckets: Vec<_> = (0..=bucket_of(cap)).map(|_| AtomicCell::new(0)).collect();
+		buckets[0].store(states as i64);
+		VersionCounts {
+			held: (0..states).map(|_| AtomicCell::new(0)).collect(),
+			buckets,
+			total: AtomicCell::new(0),
+			full_events: AtomicCell::new(0),
+		}
+	}
+
+	// State idx holds len versions now.
+	fn update(&self, idx: usize, len: usize) {
+		let last = self.held[idx].swap(len);
+		self.total.fetch_add(len as i64 - last as i64);
+		let (from, to) = (bucket_of(last), bucket_of(len));
+		if from != to {
+			self.buckets[from].fetch_sub(1);
+			self.buckets[to].fetch_add(1);
+		}
+	}
+}
+
+// One version of a state, for inspection.
+#[derive(Debug, Clone)]
+pub struct Version {
+	pub ts: u64,
+	pub value: Vec<u8>,
+	// False for slots reserved but not written yet, or reset.
+	pub written: bool,
+	// Some if value is a delta.
+	pub merge: Option<MergeOp>,
+}
+
+impl SimpleDB {
+	// Versions of a state held now, oldest first. None if no such table or state.
+	pub fn versions(&self, table: &str, key: &str) -> Option<Vec<Version>> {
+		let t = self.tables.get(table)?;
+		let r = &t.records[*t.states.get(key)?];
+		Some((0..r.len()).filter_map(|i| r.ref_at(i)).map(|dp| {
+			let dp = dp.read().unwrap();
+			Version {
+				ts: dp.ts,
+				value: dp.value.clone(),
+				written: dp.state == DataPointState::NORMAL,
+				merge: dp.merge,
+			}
+		}).collect())
+	}
+
+	/*
+		Write the newest value of each state to path, as table,state,ts,value in hex. Returns states written.
+		Versions of transactions not committed yet are included, so call it only once ingress is paused
+		and the TPG drained, as the admin checkpoint command does.
+	 */
+	pub fn checkpoint(&self, path: &str) -> io::Result<usize> {
+		let mut out = String::from("table,state,ts,value\n");
+		let mut written = 0;
+		let mut tables: Vec<_> = self.tables.iter().collect();
+		tables.sort_by_key(|(name, _)| *name);
+		for (name, t) in tables {
+			let mut states: Vec<_> = t.states.iter().collect();
+			states.sort();
+			for (state, &idx) in states {
+				let r = &t.records[idx];
+				if r.len() == 0 {
+					continue
+				}
+				let (Some(value), Some(last)) = (Table::resolve(r, r.len() - 1), r.last_clone()) else { continue };
+				let _ = write!(out, "{},{},{},", name, state, last.ts);
+				value.iter().for_each(|b| { let _ = write!(out, "{:02x}", b); });
+				out.push('\n');
+				written += 1;
+			}
+		}
+		fs::write(path, out)?;
+		Ok(written)
+	}
+
+	// From counts kept by tables. Cheap enough to take each second.
+	pub fn stats(&self) -> VersionStats {
+		let mut stats = VersionStats::default();
+		self.tables.values().for_each(|t| {
+			let counts = &t.counts;
+			stats.versions += counts.total.load().max(0) as usize;
+			stats.capacity += t.records.iter().map(|r| r.cap()).sum::<usize>();
+			stats.full_events += counts.full_events.load();
+			if stats.per_key.len() < counts.buckets.len() {
+				stats.per_key.resize(counts.buckets.len(), 0);
+			}
+			counts.buckets.iter().enumerate().for_each(|(b, n)| stats.per_key[b] += n.load().max(0) as usize);
+		});
+		stats
+	}
+}
+
 struct Table {
 	states: HashMap<String, usize>,
 	// records: HashMap<String, Vec<DataPoint<String>>>,
 	records: Vec<ringbuf::RingBuf<DataPoint<Vec<u8>>>>,
+	counts: VersionCounts,
 }
 
 #[derive(Default, Clone, Debug)]
@@ -58,6 +200,8 @@ struct DataPoint<T: Default> {
 	ts: u64,
 	value: T,
 	state: DataPointState,
+	// Some if value is a delta to be folded into the nearest base before it.
+	merge: Option<MergeOp>,
 }
 
 
@@ -81,22 +225,70 @@ impl<T: Default + Clone > ringbuf::RingBufContent for DataPoint<T> {}
 impl Table {
 	fn empty_init(keys: Vec<&str>) -> Self {
 		let mut states = HashMap::new();
-		let mut records = Vec::<ringbuf::RingBuf<DataPoint<Vec<u8>>>>::new();
+		let mut owners = Vec::new();
 		let states_per_key = CONFIG.read().unwrap().max_state_records;
+		let workers = (CONFIG.read().unwrap().worker_threads_num as usize).max(1);
 		keys.iter().enumerate().for_each(|(i, &k)|  {
 			for j in (0..states_per_key).into_iter() {
 				// For each key, we have states_per_key rows. each contains a series of time stamp.
-				states.insert(format!("{}_{}", k, j), i * states_per_key + j);
-				records.push(RingBuf::new(CONFIG.read().unwrap().ringbuffer_size as usize, Some(CONFIG.read().unwrap().ringbuffer_full_to_panic)));
+				let state = format!("{}_{}", k, j);
+				owners.push(scheduling::partition_of(&state, workers));
+				states.insert(state, i * states_per_key + j);
 			}
 			if CONFIG.read().unwrap().verbose{
 				println!("[DEBUG] key {} initiated for {} times.", k, states_per_key);
 			}
 		});
+		let counts = VersionCounts::new(owners.len(), CONFIG.read().unwrap().ringbuffer_size);
+		let by_key = CONFIG.read().unwrap().partitioned || CONFIG.read().unwrap().scheduling_policy == PolicyKind::KeyAffinity;
 		Table{
 			states: states,
-			records: records,
+			records: Self::alloc_records(owners, by_key, workers),
+			counts,
+		}
+	}
+
+	/*
+		Records of states owned by executor threads, i.e. when scheduled by key, are allocated by a thread
+		on the NUMA node of the owner. Pages land on the node touching them first.
+		Otherwise all on the current thread. owners are indexes below workers.
+	 */
+	fn alloc_records(owners: Vec<usize>, by_key: bool, workers: usize) -> Vec<RingBuf<DataPoint<Vec<u8>>>> {
+		let new_record = || RingBuf::new(CONFIG.read().unwrap().ringbuffer_size, Some(CONFIG.read().unwrap().ringbuffer_full_to_panic));
+		if !by_key {
+			return owners.iter().map(|_| new_record()).collect()
+		}
+		let nodes = topology::executor_nodes(workers);
+		let mut node_list = nodes.clone();
+		node_list.sort();
+		node_list.dedup();
+		if node_list.len() <= 1 {
+			return owners.iter().map(|_| new_record()).collect()
 		}
+		let mut records: Vec<Option<RingBuf<DataPoint<Vec<u8>>>>> = owners.iter().map(|_| None).collect();
+		thread::scope(|s| {
+			let allocators: Vec<_> = node_list.iter().map(|&node| {
+				let (owners, nodes) = (&owners, &nodes);
+				s.spawn(move || {
+					// Any executor core of the node.
+					let worker = nodes.iter().position(|&n| n == node).unwrap();
+					topology::bind(topology::Role::Executor(worker));
+					owners.iter().enumerate()
+						.filter(|(_, &owner)| nodes[owner] == node)
+						.map(|(idx, _)| (idx, new_record()))
+						.collect::<Vec<_>>()
+				})
+			}).collect();
+			for allocator in allocators {
+				for (idx, record) in allocator.join().unwrap() {
+					records[idx] = Some(record);
+				}
+			}
+		});
+		if CONFIG.read().unwrap().verbose{
+			println!("[DEBUG] records allocated on nodes {:?}.", node_list);
+		}
+		records.into_iter().map(Option::unwrap).collect()
 	}
 
 	// Called by accepted operations that being reset by aborted ancestors.
@@ -127,12 +319,9 @@ impl Table {
 			// TODO. Default value.
 			let mut value = vec![0];
 			if r.len() != 0 {
-				// Search back.
-				let to_copy_op = r.search_back( Box::new(
-						|t| {t.state == DataPointState::NORMAL}
-					), r.len() - 1); // The last index of ringbuf is len-1.
-				if to_copy_op.is_some() {
-					value = to_copy_op.unwrap().read().unwrap().value.clone()
+				// Search back. The last index of ringbuf is len-1.
+				if let Some(v) = Self::resolve(r, r.len() - 1) {
+					value = v
 				};
 
 			}
@@ -142,18 +331,17 @@ impl Table {
 		} else {
 			let (idx, new) = self.records[self.states[key]]
 				.ref_as_ordered(Box::new(move |dp: &DataPoint<Vec<u8>>| dp.ts.cmp(&ts))).unwrap();
-			let to_copy_op = r.search_back( Box::new(
-					|t| {t.state == DataPointState::NORMAL}
-				), idx);
-			let value = if to_copy_op.is_none() {
-				// Dated back to 0. Use default value.
+			// Search from the one before, the slot itself may hold the result being aborted.
+			let value = if idx == 0 {
 					vec![0]
 				} else {
-					to_copy_op.unwrap().read().unwrap().value.clone()
+					// Dated back to 0. Use default value.
+					Self::resolve(r, idx - 1).unwrap_or(vec![0])
 				};
 			let mut new_w = new.write().unwrap();
 			new_w.value = value;
 			new_w.state = DataPointState::NORMAL;
+			new_w.merge = None;
 		}
 		// WARNING: TODO Abortion deprecated here.
 	}
@@ -178,8 +366,70 @@ impl Table {
 			ts: ts,
 			value: value.clone(),
 			state: DataPointState::NORMAL,
+			merge: None,
 		};
 		self.records[self.states[key]].push(new_data_point);
+		self.count_pushed(self.states[key]);
+	}
+
+	// Count versions of state idx after a push. Full if it wrapped to none.
+	fn count_pushed(&self, idx: usize) {
+		let len = self.records[idx].len();
+		if len == 0 {
+			self.counts.full_events.fetch_add(1);
+		}
+		self.counts.update(idx, len);
+	}
+
+	// Reserve an empty slot at the end, so that writers of the same key could write back out of order.
+	fn reserve_version(&self, key: &str, ts: u64) {
+		debug_assert!(self.states.contains_key(key));
+		debug_assert!({ // Make sure is increasing order.
+			let t = &self.records[self.states[key]];
+			let n = t.last_clone();
+			n.is_none()	|| n.is_some_and(|dp| dp.ts < ts)
+		});
+		self.records[self.states[key]].push(DataPoint {
+			ts,
+			value: Vec::new(),
+			state: DataPointState::EMPTY,
+			merge: None,
+		});
+		self.count_pushed(self.states[key]);
+	}
+
+	// Write a commutative delta into the reserved slot. Empty value means identity.
+	fn write_delta(&self, key: &str, ts: u64, value: &[u8], op: MergeOp) {
+		debug_assert!(self.states.contains_key(key));
+		let obj_ref = self.records[self.states[key]]
+			.ref_as_ordered(Box::new(move |dp: &DataPoint<Vec<u8>>| dp.ts.cmp(&ts)))
+			.expect("bug. delta written without reserved slot.");
+		let mut w = obj_ref.1.write().unwrap();
+		w.value = value.to_vec();
+		w.merge = Some(op);
+		w.state = DataPointState::NORMAL;
+	}
+
+	// Resolve the value at logical index. Deltas are folded onto the nearest base before them.
+	// Empty slots are skipped. None if there is nothing written at all.
+	fn resolve(r: &RingBuf<DataPoint<Vec<u8>>>, idx: usize) -> Option<Vec<u8>> {
+		let mut deltas = Vec::new();
+		let mut base = None;
+		for i in (0..=idx).rev() {
+			let dp = r.ref_at(i)?.read().unwrap();
+			if dp.state != DataPointState::NORMAL {
+				continue
+			}
+			match dp.merge {
+				None => { base = Some(dp.value.clone()); break },
+				Some(op) => deltas.push((op, dp.value.clone())),
+			}
+		}
+		if base.is_none() && deltas.is_empty() {
+			return None
+		}
+		// No base written. Fold onto default value.
+		Some(deltas.iter().rev().fold(base.unwrap_or(vec![0]), |acc, (op, d)| op.apply(&acc, d)))
 	}
 
 	// Stage version inserts the version at in the middle. We find it first.
@@ -196,6 +446,7 @@ impl Table {
 		});
 		obj_ref.unwrap().1.write().unwrap().state = DataPointState::NORMAL;
 		obj_ref.unwrap().1.write().unwrap().value = value.clone();
+		obj_ref.unwrap().1.write().unwrap().merge = None;
 	}
 
 	fn release_version(&self, key: &str, ts: u64){
@@ -209,43 +460,146 @@ impl Table {
 				true
 			}
 		); // Should be the very first of the key.
-		let mut w = self.records[self.states[key]].ref_as_ordered(
+		let r = &self.records[self.states[key]];
+		// Deltas right after lose their base. Fold it into the next one.
+		if let Some(next) = r.ref_at(1) {
+			let mut n = next.write().unwrap();
+			if n.state == DataPointState::NORMAL && n.merge.is_some() {
+				let base = Self::resolve(r, 0).unwrap_or(vec![0]);
+				n.value = n.merge.unwrap().apply(&base, &n.value);
+				n.merge = None;
+			}
+		}
+		let mut w = r.ref_as_ordered(
 			Box::new(move |dp| dp.ts.cmp(&ts) )
 		).expect("bug").1.write().unwrap();
 		w.state = DataPointState::EMPTY;
-		self.records[self.states[key]].discard_before(1);
+		drop(w);
+		r.discard_before(1);
+		self.counts.update(self.states[key], r.len());
 	}
 
 	fn get_version(&self, key: &str, ts: u64) -> Vec<u8>{
 		// Get datapoint from ringbuf.
 		debug_assert!(self.states.contains_key(key));
-		let obj_op = self.records[self.states[key]]
-			.object_as_ordered(Box::new(move |dp: &DataPoint<Vec<u8>>| dp.ts.cmp(&ts)));
+		let r = &self.records[self.states[key]];
+		let pos_op = r.position_as_ordered(Box::new(move |dp: &DataPoint<Vec<u8>>| dp.ts.cmp(&ts)));
 		debug_assert!({
-			if obj_op.is_none(){
-				self.records[self.states[key]].dump();
+			if pos_op.is_none(){
+				r.dump();
 				false
 			} else {
 				true
 			}
 		});
-		debug_assert!(obj_op.as_ref().unwrap().state == DataPointState::NORMAL);
-		// Remove Later. Now we consider get visited the wrong place where initiated value used. Consider to add states to debug.
-		debug_assert!(obj_op.as_ref().unwrap().value.len() != 0);
-		obj_op.unwrap().value.clone()
+		let obj = r.ref_at(pos_op.unwrap()).unwrap().read().unwrap().clone();
+		debug_assert!(obj.state == DataPointState::NORMAL);
+		if obj.merge.is_none() {
+			// Remove Later. Now we consider get visited the wrong place where initiated value used. Consider to add states to debug.
+			debug_assert!(!obj.value.is_empty());
+			return obj.value
+		}
+		// Delta. Resolve lazily on read.
+		Self::resolve(r, pos_op.unwrap()).unwrap()
 	}
 }
 
 #[cfg(test)]
-mod test{
+pub(crate) mod test{
 	// Keep target and implemented traits visisble to reach the traits method.
 	use crate::database::api::*;
 	use super::*;
 
+	// Defaults allocate gigabytes of versions.
+	pub(crate) fn small_config() {
+		let mut config = CONFIG.write().unwrap();
+		config.max_state_records = 4;
+		config.ringbuffer_size = 8;
+		config.verbose = false;
+	}
+
 	#[test]
     fn test_add_table() {
+        small_config();
         let mut db = SimpleDB::new();
         db.add_table("table1", vec!["key1", "key2"]);
         assert!(db.tables.contains_key("table1"));
     }
-}
\ No newline at end of file
+
+	fn le(n: i64) -> Vec<u8> {
+		n.to_le_bytes().to_vec()
+	}
+
+	#[test]
+	fn test_delta_chain() {
+		small_config();
+		let mut db = SimpleDB::new();
+		db.add_table("t", vec!["k"]);
+		// Without a base, deltas fold onto the default value.
+		db.reserve_version("t", "k_1", 1);
+		db.write_delta("t", "k_1", 1, &le(4), MergeOp::Add);
+		assert_eq!(db.get_version("t", "k_1", 1), le(4));
+
+		db.push_version("t", "k_0", 1, &le(10));
+		db.reserve_version("t", "k_0", 2);
+		db.reserve_version("t", "k_0", 3);
+		db.reserve_version("t", "k_0", 4);
+		// Written out of order. Slots not written yet are skipped.
+		db.write_delta("t", "k_0", 3, &le(5), MergeOp::Add);
+		assert_eq!(db.get_version("t", "k_0", 3), le(15));
+		db.write_delta("t", "k_0", 2, &le(-3), MergeOp::Add);
+		db.write_delta("t", "k_0", 4, &le(9), MergeOp::Max);
+		assert_eq!(db.get_version("t", "k_0", 1), le(10));
+		assert_eq!(db.get_version("t", "k_0", 2), le(7));
+		assert_eq!(db.get_version("t", "k_0", 3), le(12));
+		assert_eq!(db.get_version("t", "k_0", 4), le(12));
+	}
+
+	#[test]
+	fn test_release_folds_base() {
+		small_config();
+		let mut db = SimpleDB::new();
+		db.add_table("t", vec!["k"]);
+		db.push_version("t", "k_0", 1, &le(10));
+		db.reserve_version("t", "k_0", 2);
+		db.write_delta("t", "k_0", 2, &le(5), MergeOp::Add);
+		db.reserve_version("t", "k_0", 3);
+		db.write_delta("t", "k_0", 3, &le(-1), MergeOp::Add);
+		db.release_version("t", "k_0", 1);
+		// The next delta became the base.
+		let versions = db.versions("t", "k_0").unwrap();
+		assert_eq!(versions.len(), 2);
+		assert_eq!((versions[0].ts, versions[0].merge, versions[0].value.clone()), (2, None, le(15)));
+		assert_eq!(versions[1].merge, Some(MergeOp::Add));
+		assert_eq!(db.get_version("t", "k_0", 2), le(15));
+		assert_eq!(db.get_version("t", "k_0", 3), le(14));
+	}
+
+	#[test]
+	fn test_stats() {
+		assert_eq!((0..9).map(bucket_of).collect::<Vec<_>>(), vec![0, 1, 2, 2, 3, 3, 3, 3, 4]);
+		small_config();
+		let mut db = SimpleDB::new();
+		db.add_table("t", vec!["k"]);
+		(1..=3).for_each(|ts| db.push_version("t", "k_0", ts, &le(ts as i64)));
+		db.reserve_version("t", "k_1", 1);
+		let stats = db.stats();
+		assert_eq!((stats.versions, stats.capacity, stats.full_events), (4, 32, 0));
+		// Two states hold none, one holds 1, one 2-3. Buckets go up to the ringbuffer_size of 8.
+		assert_eq!(stats.per_key, vec![2, 1, 1, 0, 0]);
+
+		db.release_version("t", "k_0", 1);
+		db.release_version("t", "k_0", 2);
+		assert_eq!(db.stats().per_key, vec![2, 2, 0, 0, 0]);
+		assert_eq!(db.stats().versions, 2);
+	}
+
+	#[test]
+	fn test_more_workers_than_cpus() {
+		small_config();
+		let workers = core_affinity::get_core_ids().unwrap().len() + 4;
+		// Placement by key looks up the cores of every executor.
+		let records = Table::alloc_records((0..workers).collect(), true, workers);
+		assert_eq!(records.len(), workers);
+	}
+}
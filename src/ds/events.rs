@@ -5,7 +5,105 @@ pub struct Event{
 	pub reads: Vec<String>,
 	pub write: String,
 	pub has_write: bool,
+	// States written by the same UDF call besides write. UDF returns one value for each, write first.
+	#[serde(default)]
+	pub extra_writes: Vec<String>,
+	// Commutative merge on the write state. When set, the event does not read its write state;
+	// the UDF returns a delta that is folded into the state lazily when someone reads it.
+	// It applies to extra_writes as well: each of them gets a delta by the same operation.
+	#[serde(default)]
+	pub merge: Option<MergeOp>,
+	// Earlier events in the same transaction whose written results this event reads.
+	// Their values are passed to UDF after the values of reads, in the order declared.
+	#[serde(default)]
+	pub inputs: Vec<usize>,
+	// Earlier event in the same transaction whose result is the row of all reads and writes of this event.
+	// Rows given in the transaction request are ignored. The event is linked to TPG once the row is known.
+	#[serde(default)]
+	pub key_from: Option<usize>,
 
 	#[serde(skip)]
 	pub sa_idx: usize,
 }
+
+impl Event {
+	// All states written. Empty if has_write is false.
+	pub fn all_writes(&self) -> Vec<&String> {
+		if !self.has_write {
+			return Vec::new()
+		}
+		std::iter::once(&self.write).chain(self.extra_writes.iter()).collect()
+	}
+}
+
+/*
+	Commutative merge operations.
+	Numeric operations treat values as little-endian signed integers (at most 8 bytes),
+	which is how UDF results are written back. An empty delta is the identity of every operation,
+	it's what an aborted merge event leaves behind.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeOp {
+	Add,
+	Max,
+	Min,
+	Or,
+	Append,
+}
+
+impl MergeOp {
+	// Fold delta into base.
+	pub fn apply(&self, base: &[u8], delta: &[u8]) -> Vec<u8> {
+		if delta.is_empty() {
+			return base.to_vec()
+		}
+		match self {
+			MergeOp::Add => to_le(from_le(base).wrapping_add(from_le(delta)), base.len().max(delta.len())),
+			MergeOp::Max => to_le(from_le(base).max(from_le(delta)), base.len().max(delta.len())),
+			MergeOp::Min => to_le(from_le(base).min(from_le(delta)), base.len().max(delta.len())),
+			MergeOp::Or => {
+				let mut ret = vec![0; base.len().max(delta.len())];
+				base.iter().enumerate().for_each(|(i, b)| ret[i] |= b);
+				delta.iter().enumerate().for_each(|(i, b)| ret[i] |= b);
+				ret
+			},
+			MergeOp::Append => {
+				let mut ret = base.to_vec();
+				ret.extend_from_slice(delta);
+				ret
+			},
+		}
+	}
+}
+
+fn from_le(v: &[u8]) -> i64 {
+	let len = v.len().min(8);
+	let mut buf = if len > 0 && v[len - 1] & 0x80 != 0 { [0xff; 8] } else { [0; 8] };
+	buf[..len].copy_from_slice(&v[..len]);
+	i64::from_le_bytes(buf)
+}
+
+fn to_le(n: i64, width: usize) -> Vec<u8> {
+	n.to_le_bytes()[..width.min(8)].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_merge_op_apply() {
+		let base = 5i32.to_le_bytes().to_vec();
+		let delta = (-7i32).to_le_bytes().to_vec();
+		assert_eq!(MergeOp::Add.apply(&base, &delta), (-2i32).to_le_bytes().to_vec());
+		assert_eq!(MergeOp::Max.apply(&base, &delta), base);
+		assert_eq!(MergeOp::Min.apply(&base, &delta), delta);
+		assert_eq!(MergeOp::Or.apply(&[0b01], &[0b10, 1]), vec![0b11, 1]);
+		assert_eq!(MergeOp::Append.apply(&[1], &[2, 3]), vec![1, 2, 3]);
+		// Default value of a state is a single zero byte.
+		assert_eq!(MergeOp::Add.apply(&[0], &delta), delta);
+		// Empty delta means aborted.
+		assert_eq!(MergeOp::Add.apply(&base, &[]), base);
+	}
+}
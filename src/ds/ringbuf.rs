@@ -145,6 +145,15 @@ impl<T> RingBuf<T>
 		}
 	}
 
+	// Reference by logical index, counted from the head.
+	pub fn ref_at(&self, idx: usize) -> Option<&RwLock<T>> {
+		if idx >= self.len() {
+			None
+		} else {
+			Some(self.buf.index((self.start() + idx) % self.cap))
+		}
+	}
+
 	// Dump used for debugging. Print content for checking;
 	pub fn dump(&self){
 		println!("ringbuf.start {}; ringbuf.end {}.", self.start(), self.end());
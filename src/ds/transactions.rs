@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use crate::ds::events as ev;
 use once_cell::sync::OnceCell;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Default)]
 pub struct Txn{
@@ -10,12 +10,43 @@ pub struct Txn{
 		Fields passed from FFI __init_sfc. 
 	 */
 	pub es: Vec<ev::Event>,
+	// Retry aborted transactions of this template. None to report abortion directly.
+	pub retry: Option<RetryPolicy>,
+	// Abort transactions not committed in this time since their ts. None to wait forever.
+	pub timeout_ns: Option<u64>,
 	// To be added.
 
     pub all_reads_index_map: HashMap<String, usize>,
 	pub all_reads_length: usize,
 }
 
+/*
+	Retry policy of aborted transactions.
+	A new attempt is deposited with a new timestamp, and only the final outcome is reported to VNF.
+ */
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RetryPolicy {
+	// Attempts in total, including the first one.
+	pub max_attempts: u32,
+	// Abort codes to retry. Empty to retry on any code.
+	#[serde(default)]
+	pub retry_on: Vec<u32>,
+	// Backoff before the first retry. Doubled for each later attempt.
+	#[serde(default)]
+	pub backoff_ns: u64,
+}
+
+impl RetryPolicy {
+	// Backoff before the given attempt, if it's allowed. The first attempt is 0.
+	pub fn backoff(&self, attempt: u32, abort_code: u32) -> Option<u64> {
+		if attempt >= self.max_attempts 
+			|| (!self.retry_on.is_empty() && !self.retry_on.contains(&abort_code)) {
+			return None
+		}
+		Some(self.backoff_ns.saturating_mul(1 << (attempt.max(1) - 1).min(32)))
+	}
+}
+
 pub static TXN_TEMPLATES: OnceCell::<Vec<Txn>> = OnceCell::new();
 
 impl Txn {
@@ -29,19 +60,47 @@ impl Txn {
         // Initialize variables
         let mut all_reads = Vec::new();
         let mut all_writes = HashMap::new();
+        let writers: Vec<bool> = self.es.iter().map(|e| e.has_write).collect();
+        let dynamics: Vec<bool> = self.es.iter().map(|e| e.key_from.is_some()).collect();
+        let states_written: Vec<Vec<String>> = self.es.iter().map(|e| e.all_writes().into_iter().cloned().collect()).collect();
 
         // Iterate through each event in the transaction
         self.es.iter_mut().enumerate().for_each(|(idx,event)| {
 			// To make close if cancel write dependency.
-			event.reads.push(event.write.clone());
+			// Merge events do not read their write state. They record deltas instead.
+			if event.merge.is_none() {
+				let writes: Vec<String> = event.all_writes().into_iter().cloned().collect();
+				event.reads.extend(writes);
+			}
 			event.reads.sort();
 			event.reads.dedup();
 			event.sa_idx = idx;
+			// Intra-transaction inputs are linked by their write key. So they must be earlier writers.
+			event.inputs.iter().for_each(|&i| {
+				if i >= idx || !writers[i] {
+					panic!("Event {} reads result of event {}, which is not an earlier writer in the transaction.", idx, i);
+				}
+				// Write key of dynamic event is unknown when the transaction is received.
+				if dynamics[i] {
+					panic!("Event {} reads result of event {}, whose key is dynamic.", idx, i);
+				}
+				// It's linked to the last writer of the key. Rows of dynamic events could not be checked against it.
+				let state = &states_written[i][0];
+				if (i + 1..idx).any(|j| dynamics[j] && states_written[j].contains(state)) {
+					panic!("Event {} reads result of event {}, whose state could be written by a dynamic event in between.", idx, i);
+				}
+				if event.key_from.is_some() && event.reads.contains(state) {
+					panic!("Event {} reads result of event {}, whose state it could read itself by a dynamic key.", idx, i);
+				}
+			});
+			if event.key_from.is_some_and(|i| i >= idx) {
+				panic!("Event {} takes key from event {}, which is not earlier in the transaction.", idx, event.key_from.unwrap());
+			}
 
             // Add reads to all_reads
             all_reads.extend(event.reads.iter().cloned());
             // Record write field in all_writes
-            all_writes.insert(event.write.clone(), ());
+            event.all_writes().into_iter().for_each(|w| { all_writes.insert(w.clone(), ()); });
             // Check for duplicate writes
             // if all_writes.get(&event.write).is_some() {
             //     panic!("Duplicate writes detected for key: {}", event.write);
@@ -57,3 +116,25 @@ impl Txn {
             .enumerate().map(|(idx, s)| (s.clone(), idx)).collect();
     }
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_retry_backoff() {
+		let policy = RetryPolicy { max_attempts: 4, retry_on: vec![7], backoff_ns: 100 };
+		// Doubled for each retry.
+		assert_eq!(policy.backoff(1, 7), Some(100));
+		assert_eq!(policy.backoff(2, 7), Some(200));
+		assert_eq!(policy.backoff(3, 7), Some(400));
+		// Out of attempts.
+		assert_eq!(policy.backoff(4, 7), None);
+		// Code not retried.
+		assert_eq!(policy.backoff(1, 8), None);
+
+		let any = RetryPolicy { max_attempts: u32::MAX, retry_on: Vec::new(), backoff_ns: 1 << 40 };
+		assert_eq!(any.backoff(1, 8), Some(1 << 40));
+		assert_eq!(any.backoff(100, 8), Some(u64::MAX));
+	}
+}
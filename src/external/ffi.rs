@@ -26,7 +26,8 @@ unsafe extern "C++" {
 	pub fn Init_SFC(argc: i32, argv: Vec<String>) -> String;
 	pub fn VNFThread(c: i32, v: Vec<String>);
 	pub fn execute_sa_udf(txnReqId_jni: u64, saIdx: i32, value: Vec<u8>, param_count: i32) -> Vec<u8>;
-	pub fn txn_finished(txnReqId_jni: u64) -> i32;
+	// Runtimes only implementing txn_finished(txnReqId_jni) get a default dropping the reason.
+	pub fn txn_finished_with_reason(txnReqId_jni: u64, abort_code: u32, abort_message: String) -> i32;
 }
 
 // #[derive(Deserialize)]
@@ -50,8 +51,9 @@ unsafe extern "C++" {
 use std::{collections::HashMap, mem};
 
 use serde::{Deserialize, Serialize};
-use crate::{config::CONFIG, ds::transactions::{Txn, TXN_TEMPLATES}};
+use crate::{config::CONFIG, ds::transactions::{RetryPolicy, Txn, TXN_TEMPLATES}};
 use crate::ds::events as ev;
+use crate::worker::idle;
 
 // {
 // 	"app": [{
@@ -100,6 +102,10 @@ struct AppInfo {
 struct TransactionData {
     #[serde(rename = "StateAccesses")]
     state_accesses: Vec<ev::Event>,
+    #[serde(rename = "RetryPolicy", default)]
+    retry: Option<RetryPolicy>,
+    #[serde(rename = "TimeoutNs", default)]
+    timeout_ns: Option<u64>,
 }
 
 
@@ -111,7 +117,19 @@ pub struct TxnMessage {
 	// When transaction created, they pointes the column to read; Here in runtime, they point out which row to read.
 	// indexes are displayed in vector for each event.
 	pub reads_idx: Vec<Vec<usize>>,
-	pub write_idx: Vec<usize> 
+	pub write_idx: Vec<usize>,
+	// Rows of extra_writes for each event. Could be omitted if no event has extra writes.
+	#[serde(default)]
+	pub extra_writes_idx: Vec<Vec<usize>>,
+	// Attempts made before. Set by engine when retrying.
+	#[serde(default)]
+	pub attempt: u32,
+	// Abort if not committed by then. Compared with current_time_ns. Overrides the timeout of template.
+	#[serde(default)]
+	pub deadline_ns: Option<u64>,
+	// Priority class. Higher is served first. Capped by CONFIG.priority_levels.
+	#[serde(default)]
+	pub priority: u8,
 }
 
 pub fn deposit_transaction(a: String){
@@ -121,7 +139,7 @@ pub fn deposit_transaction(a: String){
 		panic!("required index out of range.");
 	};
 	match super::pipe::PIPE_IN.get().unwrap().send(msg){
-		Ok(_) => (),
+		Ok(_) => idle::wake_all(),
 		Err(e) => println!("Error sending message to pipe: {}", e),
 	}
 }
@@ -145,6 +163,8 @@ pub(crate) fn init_sfc(argc: i32, argv: Vec<String>) {
         for transaction_data in app_info.transactions {
             let txn = Txn {
                 es: transaction_data.state_accesses,
+                retry: transaction_data.retry,
+                timeout_ns: transaction_data.timeout_ns,
                 all_reads_length: 0,
 				all_reads_index_map: HashMap::new(),
             };
@@ -171,8 +191,9 @@ pub(crate) fn init_sfc(argc: i32, argv: Vec<String>) {
 pub(crate) fn all_variables() -> Vec<&'static str> { // WARN: lifecycle.
 	let mut ret: Vec<&'static str> = Vec::new();
 	TXN_TEMPLATES.get().unwrap().iter().for_each(|txn|{
+		// Writes are included, since merge events write states they do not read.
 		let mut all_reads: Vec<&String> = txn.es.iter()
-            .flat_map(|en| en.reads.iter())
+            .flat_map(|en| en.reads.iter().chain(en.all_writes()))
             .collect();
 
         // Sort and deduplicate reads
@@ -189,12 +210,34 @@ pub(crate) fn vnf_thread(c: i32, v: Vec<String>) {
 	ffi::VNFThread(c, v)
 }
 
-// Return result and abortion sign. If abortion  required, the first bool is true.
-pub(crate) fn execute_event(txn_req_id: u64, sa_idx: i32, value: Vec<u8>, param_count: i32) -> (bool, Vec<u8>) {
+// Abort codes raised by engine. Codes from UDF are application-defined, and should stay below these.
+pub(crate) const ABORT_MALFORMED_RESULT: u32 = u32::MAX;
+pub(crate) const ABORT_INVALID_KEY: u32 = u32::MAX - 1;
+pub(crate) const ABORT_ILLEGAL_REQUEST: u32 = u32::MAX - 2;
+pub(crate) const ABORT_TIMEOUT: u32 = u32::MAX - 3;
+pub(crate) const ABORT_STUCK: u32 = u32::MAX - 4;
+pub(crate) const ABORT_SHUTDOWN: u32 = u32::MAX - 5;
+
+// Why a transaction aborted. Reported to VNF with the outcome.
+#[derive(Debug, Clone, Default)]
+pub struct AbortReason {
+	pub code: u32,
+	pub message: String,
+}
+
+impl AbortReason {
+	pub fn new(code: u32, message: &str) -> Self {
+		AbortReason { code, message: message.to_string() }
+	}
+}
+
+// Return result and abort reason. If abortion required, the reason is returned instead of result.
+pub(crate) fn execute_event(txn_req_id: u64, sa_idx: i32, value: Vec<u8>, param_count: i32) -> (Option<AbortReason>, Vec<u8>) {
 	let res = ffi::execute_sa_udf(txn_req_id, sa_idx, value.into(), param_count);
 	/* Res should be composed of:
+		- Abortion. An int. 0 if not aborted, otherwise the abort code.
 		- Result to be written back. Here it's an int.
-		- Abortion. Another int.
+			When aborted, it's the abort message instead. UTF-8, could be empty.
 	*/ 
     // Parse the integers from the string
 	let abortion = u32::from_le_bytes([
@@ -204,11 +247,82 @@ pub(crate) fn execute_event(txn_req_id: u64, sa_idx: i32, value: Vec<u8>, param_
         res[3],
     ]);
 
-	(abortion == 1, res[4..].to_vec())
+	if abortion != 0 {
+		return (Some(AbortReason {
+			code: abortion,
+			message: String::from_utf8_lossy(&res[4..]).into_owned(),
+		}), Vec::new())
+	}
+	(None, res[4..].to_vec())
+}
+
+// Row index from UDF result. Little-endian unsigned integer.
+pub(crate) fn row_from_result(res: &[u8]) -> usize {
+	let mut buf = [0u8; 8];
+	let len = res.len().min(8);
+	buf[..len].copy_from_slice(&res[..len]);
+	u64::from_le_bytes(buf) as usize
+}
+
+// Split UDF result of an event writing multiple states.
+/* Result should be composed of, for each state written:
+	- Length of the value. u32 in little endian.
+	- Value.
+	None if the result is malformed.
+*/
+pub(crate) fn split_results(res: &[u8], n: usize) -> Option<Vec<Vec<u8>>> {
+	let mut ret = Vec::with_capacity(n);
+	let mut cur = 0;
+	for _ in 0..n {
+		if res.len() < cur + 4 {
+			return None
+		}
+		let len = u32::from_le_bytes([res[cur], res[cur + 1], res[cur + 2], res[cur + 3]]) as usize;
+		cur += 4;
+		if res.len() < cur + len {
+			return None
+		}
+		ret.push(res[cur..cur + len].to_vec());
+		cur += len;
+	}
+	Some(ret)
 }
 
-// TODO. Return result. ILLEGAL, SUCCESS, ABORTED.
-pub(crate) fn txn_finished_sign(txn_req_id: u64) -> i32 {
-	ffi::txn_finished(txn_req_id)
+// Inform VNF of the outcome. Abort code 0 for committed txn.
+pub(crate) fn txn_finished_sign(txn_req_id: u64, abortion: Option<&AbortReason>) -> i32 {
+	match abortion {
+		Some(reason) => ffi::txn_finished_with_reason(txn_req_id, reason.code, reason.message.clone()),
+		None => ffi::txn_finished_with_reason(txn_req_id, 0, String::new()),
+	}
 }
 
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Length prefixed value, as UDFs write them.
+	fn prefixed(v: &[u8]) -> Vec<u8> {
+		let mut ret = (v.len() as u32).to_le_bytes().to_vec();
+		ret.extend_from_slice(v);
+		ret
+	}
+
+	#[test]
+	fn test_split_results() {
+		assert_eq!(split_results(&[], 0), Some(Vec::new()));
+		assert_eq!(split_results(&[1, 2, 3], 0), Some(Vec::new()));
+
+		let mut res = prefixed(&[1, 2]);
+		res.extend(prefixed(&[]));
+		res.extend(prefixed(&[3]));
+		assert_eq!(split_results(&res, 3), Some(vec![vec![1, 2], vec![], vec![3]]));
+
+		// Length prefix cut short.
+		assert_eq!(split_results(&res[..res.len() - 3], 3), None);
+		assert_eq!(split_results(&[1, 0], 1), None);
+		assert_eq!(split_results(&prefixed(&[1, 2]), 2), None);
+		// Length beyond the buffer.
+		assert_eq!(split_results(&res[..res.len() - 1], 3), None);
+		assert_eq!(split_results(&[9, 0, 0, 0, 1], 1), None);
+	}
+}
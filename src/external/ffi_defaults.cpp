@@ -0,0 +1,10 @@
+#include "ffi.h"
+
+// Defaults of interfaces added after the runtime. Weak, so those the runtime implements win.
+
+// Runtimes before abort reasons only know the request finished.
+__attribute__((weak)) int32_t txn_finished_with_reason(uint64_t txnReqId_jni, uint32_t abort_code, rust::String abort_message) {
+    (void)abort_code;
+    (void)abort_message;
+    return txn_finished(txnReqId_jni);
+}
@@ -7,6 +7,8 @@ use super::ffi;
 
 // TODO: How to expose a lazy inited object?
 pub(crate) static PIPE_IN: OnceCell<SyncSender<ffi::TxnMessage>> = OnceCell::new();
+// Retried transactions, with the time to deposit. Unbounded, so workers never block on it.
+pub(crate) static RETRY_IN: OnceCell<Sender<(u64, ffi::TxnMessage)>> = OnceCell::new();
 
 pub fn init() -> Receiver<ffi::TxnMessage> {
 	// TODO: Verify really blocks when the channel is full?
@@ -14,3 +16,9 @@ pub fn init() -> Receiver<ffi::TxnMessage> {
 	PIPE_IN.set(tx.clone()).expect("Failed to set pipe.");
 	rx
 }
+
+pub fn init_retry() -> Receiver<(u64, ffi::TxnMessage)> {
+	let (tx, rx) = channel();
+	RETRY_IN.set(tx).expect("Failed to set retry pipe.");
+	rx
+}
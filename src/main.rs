@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 use std::env;
 use std::thread;
+use std::panic;
 
 mod ds;
 mod config;
@@ -10,6 +11,8 @@ mod external;
 mod database;
 mod monitor;
 mod tpg;
+mod topology;
+mod admin;
 
 use database::{
     api::Database, 
@@ -24,11 +27,18 @@ use worker::{
     construct_thread::GRACEFUL_SHUTDOWN,
 };
 use rayon::prelude::*;
+use topology::Role;
 
 fn main() {
     // Parse command line arguments
     let args: Vec<String> = env::args().collect();
 
+    // Offline analysis of monitor logs. Does not start the engine.
+    if args.get(1).is_some_and(|a| a == "analyze") {
+        monitor::analysis::run(&args[2..]);
+        return;
+    }
+
     // Get the file path from command line arguments or use default
     let file_path = if args.len() > 1 {
         PathBuf::from(&args[1])
@@ -37,10 +47,12 @@ fn main() {
     };
 
     config::init(file_path);
-    utils::bind_to_cpu_core(0);
+    topology::check();
+    topology::bind(Role::Constructor);
     metrics::init();
 
     ffi::init_sfc(0, Vec::new());
+    monitor::latency::init();
 
     // Initiate Database.
     let mut db = SimpleDB::new();
@@ -52,12 +64,24 @@ fn main() {
     */
     let _ = TPG.set(Tpg::new(ffi::all_variables()));
 
+    if config::CONFIG.read().unwrap().dump_tpg_on_panic {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            match tpg::dot::dump(&tpg::dot::DotFilter::default()) {
+                Ok(path) => eprintln!("TPG dumped to {}", path),
+                Err(err) => eprintln!("Failed to dump TPG: {}", err),
+            }
+        }));
+    }
+
     /*
         Spawn Vnf threads and bind to core.
      */
     let vnf_guard = 
         thread::spawn(move || {
-            // VNF thread bind in runtime.
+            // VNF threads inherit the cores, unless bound again in runtime.
+            topology::bind(Role::Vnf);
             ffi::vnf_thread(0, Vec::new());
     });
 
@@ -70,7 +94,7 @@ fn main() {
     let guards: Vec<_> = (config::CONFIG.read().unwrap().vnf_threads_num + 1..worker_thread_ends)
         .into_par_iter().map(|tid| {
             thread::spawn(move || {
-                utils::bind_to_cpu_core(tid as usize);
+                topology::bind(Role::Executor((tid - config::CONFIG.read().unwrap().vnf_threads_num - 1) as usize));
                 execute_thread(tid as usize)
             })
     }).collect();
@@ -79,10 +103,34 @@ fn main() {
         Spawn monitor thread. if required.
      */
     let monitor_guards = thread::spawn(move || {
-        // utils::bind_to_cpu_core();
+        topology::bind(Role::Monitor);
         metrics::monitor_thread(worker_thread_ends as usize);
     });
 
+    /*
+        Spawn metrics exporter thread. Returns at once if no metrics_addr.
+     */
+    let exporter_guard = thread::spawn(move || {
+        topology::bind(Role::Monitor);
+        monitor::exporter::exporter_thread();
+    });
+
+    /*
+        Spawn watchdog thread. Returns at once if watchdog_interval_ms is 0.
+     */
+    let watchdog_guard = thread::spawn(move || {
+        topology::bind(Role::Monitor);
+        worker::watchdog::watchdog_thread(worker_thread_ends as usize);
+    });
+
+    /*
+        Spawn admin socket thread. Returns at once if no admin_socket.
+     */
+    let admin_guard = thread::spawn(move || {
+        topology::bind(Role::Monitor);
+        admin::admin_thread();
+    });
+
     // Register a handler for graceful shutdown
     ctrlc::set_handler(move || {
         println!("Exiting. Please wait till all tasks finished.");
@@ -97,4 +145,7 @@ fn main() {
     };
     vnf_guard.join().unwrap();
     monitor_guards.join().unwrap();
+    exporter_guard.join().unwrap();
+    watchdog_guard.join().unwrap();
+    admin_guard.join().unwrap();
 }
\ No newline at end of file
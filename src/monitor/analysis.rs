@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use serde::Serialize;
+
+/*
+	Offline analysis of monitor logs in a log directory. Run as `DB4NFV analyze [log_dir] [--format text|csv|json]`.
+	- Throughput per second, from history.csv. Transactions entering the TPG, and those whose last event finished.
+	- Latency percentiles of each stage, from history.csv, in ns.
+	- Abort rate of each template, from cnt.csv, against commits counted in latency.csv.
+	- Distribution of local queue sizes of each executor after fetching from the global queue, from history.csv.
+	history.csv only holds traced transactions. With trace_sample_n above 1, throughput is of those sampled.
+ */
+#[derive(Serialize, Default)]
+pub struct Report {
+	pub throughput: Vec<Throughput>,
+	pub latency: Vec<Distribution>,
+	pub aborts: Vec<AbortRate>,
+	pub queue_sizes: Vec<Distribution>,
+}
+
+#[derive(Serialize)]
+pub struct Throughput {
+	pub second: u64,
+	pub ingress: u64,
+	pub done: u64,
+}
+
+#[derive(Serialize)]
+pub struct Distribution {
+	// Stage of latency, or thread of queue size.
+	pub name: String,
+	pub count: usize,
+	pub min: u64,
+	pub p50: u64,
+	pub p90: u64,
+	pub p99: u64,
+	pub max: u64,
+}
+
+#[derive(Serialize)]
+pub struct AbortRate {
+	pub template: u16,
+	pub aborts: u64,
+	// None without latency.csv.
+	pub commits: Option<u64>,
+	pub rate: Option<f64>,
+}
+
+impl Distribution {
+	fn of(name: String, mut values: Vec<u64>) -> Option<Self> {
+		if values.is_empty() {
+			return None
+		}
+		values.sort_unstable();
+		let at = |q: f64| values[((q * values.len() as f64).ceil() as usize).clamp(1, values.len()) - 1];
+		Some(Distribution {
+			name,
+			count: values.len(),
+			min: values[0],
+			p50: at(0.5),
+			p90: at(0.9),
+			p99: at(0.99),
+			max: values[values.len() - 1],
+		})
+	}
+}
+
+// Stages a transaction went through, from its history records.
+#[derive(Default)]
+struct TxnTrace {
+	dispatched: Option<u64>,
+	linked: Option<u64>,
+	first_fetch: Option<u64>,
+	last_done: Option<u64>,
+	// Fetch starts of events not finished yet.
+	fetching: HashMap<i32, u64>,
+	executions: Vec<u64>,
+}
+
+pub fn analyze(log_dir: &str) -> Result<Report, String> {
+	let history = fs::read_to_string(format!("{}/history.csv", log_dir))
+		.map_err(|e| format!("Failed to read {}/history.csv: {}", log_dir, e))?;
+	let cnt = fs::read_to_string(format!("{}/cnt.csv", log_dir)).unwrap_or_default();
+	let latency = fs::read_to_string(format!("{}/latency.csv", log_dir)).ok();
+	Ok(parse(&history, &cnt, latency.as_deref()))
+}
+
+// Report from the contents of history.csv, cnt.csv and latency.csv if any.
+fn parse(history: &str, cnt: &str, latency: Option<&str>) -> Report {
+	let mut txns: HashMap<u64, TxnTrace> = HashMap::new();
+	let mut queue_sizes: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+	for line in history.lines() {
+		// tid,role,logged at, then the record.
+		let f: Vec<&str> = line.split(',').collect();
+		if f.len() < 6 {
+			continue
+		}
+		let num = |i: usize| f.get(i).and_then(|v| v.parse::<u64>().ok());
+		if f[4] == "thread local queue size" {
+			if let Some(len) = num(6) {
+				queue_sizes.entry(format!("thread {}", f[3])).or_default().push(len);
+			}
+			continue
+		}
+		let (Some(req_id), Some(at)) = (num(3), num(5)) else { continue };
+		let t = txns.entry(req_id).or_default();
+		let idx = f.get(6).and_then(|v| v.parse::<i32>().ok());
+		match f[4] {
+			"dispatched_from_vnf" => t.dispatched = Some(at),
+			"linked_to_tpg" => t.linked = Some(at),
+			"fetching_value" => {
+				t.first_fetch = Some(t.first_fetch.map_or(at, |v| v.min(at)));
+				t.fetching.insert(idx.unwrap_or(-1), at);
+			},
+			"execution_done" => {
+				if let Some(start) = t.fetching.remove(&idx.unwrap_or(-1)) {
+					t.executions.push(at.saturating_sub(start));
+				}
+			},
+			"accept" | "abort" => t.last_done = Some(t.last_done.map_or(at, |v| v.max(at))),
+			_ => {},
+		}
+	}
+
+	let mut report = Report::default();
+
+	// Throughput, in seconds since the first ingress.
+	let start = txns.values().filter_map(|t| t.dispatched).min().unwrap_or(0);
+	let mut per_second: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+	for t in txns.values() {
+		if let Some(at) = t.dispatched {
+			per_second.entry(at.saturating_sub(start) / 1_000_000_000).or_default().0 += 1;
+		}
+		if let Some(at) = t.last_done {
+			per_second.entry(at.saturating_sub(start) / 1_000_000_000).or_default().1 += 1;
+		}
+	}
+	report.throughput = per_second.into_iter()
+		.map(|(second, (ingress, done))| Throughput { second, ingress, done })
+		.collect();
+
+	let gap = |from: Option<u64>, to: Option<u64>| Some(to?.saturating_sub(from?));
+	let stages: [(&str, Vec<u64>); 4] = [
+		("ingress_to_link", txns.values().filter_map(|t| gap(t.dispatched, t.linked)).collect()),
+		("link_to_first_fetch", txns.values().filter_map(|t| gap(t.linked, t.first_fetch)).collect()),
+		("execution", txns.values().flat_map(|t| t.executions.iter().copied()).collect()),
+		("ingress_to_done", txns.values().filter_map(|t| gap(t.dispatched, t.last_done)).collect()),
+	];
+	report.latency = stages.into_iter()
+		.filter_map(|(name, values)| Distribution::of(name.to_string(), values))
+		.collect();
+
+	report.queue_sizes = queue_sizes.into_iter()
+		.filter_map(|(name, values)| Distribution::of(name, values))
+		.collect();
+
+	// cnt.csv holds a snapshot of counters each second. Take the last of each.
+	let mut last: HashMap<(&str, &str), u64> = HashMap::new();
+	for line in cnt.lines() {
+		let f: Vec<&str> = line.split(',').collect();
+		if f.len() == 4 {
+			if let Ok(v) = f[3].parse() {
+				last.insert((f[0], f[2]), v);
+			}
+		}
+	}
+	let mut aborts: BTreeMap<u16, u64> = BTreeMap::new();
+	for ((_, key), v) in last {
+		// txn.abort.<template>.<code>
+		let mut parts = key.split('.').skip(2);
+		if key.starts_with("txn.abort.") {
+			if let Some(Ok(template)) = parts.next().map(str::parse) {
+				*aborts.entry(template).or_default() += v;
+			}
+		}
+	}
+	// latency.csv: template,stage,count,...
+	let commits: Option<HashMap<u16, u64>> = latency.map(|l| l.lines()
+		.map(|line| line.split(',').collect::<Vec<_>>())
+		.filter(|f| f.len() > 2 && f[1] == "ingress_to_commit")
+		.filter_map(|f| Some((f[0].parse().ok()?, f[2].parse().ok()?)))
+		.collect());
+	report.aborts = aborts.into_iter().map(|(template, aborts)| {
+		let commits = commits.as_ref().map(|c| c.get(&template).copied().unwrap_or(0));
+		AbortRate {
+			template,
+			aborts,
+			commits,
+			rate: commits.map(|c| aborts as f64 / (aborts + c).max(1) as f64),
+		}
+	}).collect();
+	report
+}
+
+pub fn format_text(report: &Report) -> String {
+	let mut out = String::new();
+	out += "== Throughput (txn/s)\nsecond\tingress\tdone\n";
+	report.throughput.iter().for_each(|t| out += &format!("{}\t{}\t{}\n", t.second, t.ingress, t.done));
+	out += "\n== Latency (ns)\n";
+	out += &format_distributions(&report.latency, "stage");
+	out += "\n== Aborts\ntemplate\taborts\tcommits\trate\n";
+	report.aborts.iter().for_each(|a| out += &format!("{}\t{}\t{}\t{}\n",
+		a.template, a.aborts,
+		a.commits.map_or(String::from("-"), |c| c.to_string()),
+		a.rate.map_or(String::from("-"), |r| format!("{:.4}", r)),
+	));
+	out += "\n== Local queue sizes\n";
+	out += &format_distributions(&report.queue_sizes, "thread");
+	out
+}
+
+fn format_distributions(ds: &[Distribution], name: &str) -> String {
+	let mut out = format!("{}\tcount\tmin\tp50\tp90\tp99\tmax\n", name);
+	ds.iter().for_each(|d| out += &format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", d.name, d.count, d.min, d.p50, d.p90, d.p99, d.max));
+	out
+}
+
+// One table of section,key,field,value rows.
+pub fn format_csv(report: &Report) -> String {
+	let mut out = String::from("section,key,field,value\n");
+	report.throughput.iter().for_each(|t| {
+		out += &format!("throughput,{},ingress,{}\nthroughput,{},done,{}\n", t.second, t.ingress, t.second, t.done);
+	});
+	for (section, ds) in [("latency", &report.latency), ("queue_size", &report.queue_sizes)] {
+		ds.iter().for_each(|d| {
+			for (field, v) in [("count", d.count as u64), ("min", d.min), ("p50", d.p50), ("p90", d.p90), ("p99", d.p99), ("max", d.max)] {
+				out += &format!("{},{},{},{}\n", section, d.name, field, v);
+			}
+		});
+	}
+	report.aborts.iter().for_each(|a| {
+		out += &format!("aborts,{},aborts,{}\n", a.template, a.aborts);
+		if let (Some(c), Some(r)) = (a.commits, a.rate) {
+			out += &format!("aborts,{},commits,{}\naborts,{},rate,{}\n", a.template, c, a.template, r);
+		}
+	});
+	out
+}
+
+// Entry of the analyze subcommand. Args after the subcommand.
+pub fn run(args: &[String]) {
+	let mut log_dir = String::from("./perf");
+	let mut format = String::from("text");
+	let mut it = args.iter();
+	while let Some(arg) = it.next() {
+		match arg.as_str() {
+			"--format" => format = it.next().cloned().unwrap_or(format),
+			_ => log_dir = arg.clone(),
+		}
+	}
+	let report = match analyze(&log_dir) {
+		Ok(report) => report,
+		Err(err) => {
+			eprintln!("{}", err);
+			std::process::exit(1);
+		}
+	};
+	match format.as_str() {
+		"text" => print!("{}", format_text(&report)),
+		"csv" => print!("{}", format_csv(&report)),
+		"json" => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+		_ => {
+			eprintln!("Unknown format {}. Use text, csv or json.", format);
+			std::process::exit(1);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// tid,role,logged at, then the record.
+	const HISTORY: &str = "\
+0,CONSTRUCTOR,0,1,dispatched_from_vnf,1000000000
+0,CONSTRUCTOR,0,1,linked_to_tpg,1000000100
+1,EXECUTOR,0,1,fetching_value,1000000300,0
+1,EXECUTOR,0,1,execution_done,1000000350,0
+1,EXECUTOR,0,1,accept,1000000400,0
+0,CONSTRUCTOR,0,2,dispatched_from_vnf,1500000000
+0,CONSTRUCTOR,0,2,linked_to_tpg,1500000300
+2,EXECUTOR,0,2,fetching_value,2600000000,0
+2,EXECUTOR,0,2,execution_done,2600000150,0
+2,EXECUTOR,0,2,abort,2600000200,0
+1,EXECUTOR,0,1,thread local queue size,1000000200,3
+1,EXECUTOR,0,1,thread local queue size,1000000250,1
+";
+
+	// Snapshots of counters each second. tid,role,key,value.
+	const CNT: &str = "\
+0,EXECUTOR,txn.abort.0.1,1
+0,EXECUTOR,txn.abort.0.1,2
+1,EXECUTOR,txn.abort.0.7,1
+1,EXECUTOR,txn.abort.3.1,4
+1,EXECUTOR,txn.abort.x.1,9
+1,EXECUTOR,evnode.accept,5
+";
+
+	const LATENCY: &str = "\
+0,ingress_to_commit,6,1,2,3,4
+0,link_to_ready,6,1,2,3,4
+";
+
+	#[test]
+	fn test_throughput() {
+		let report = parse(HISTORY, "", None);
+		let seconds: Vec<_> = report.throughput.iter().map(|t| (t.second, t.ingress, t.done)).collect();
+		// Txn 1 in and done in second 0, txn 2 in then, and done in second 1.
+		assert_eq!(seconds, vec![(0, 2, 1), (1, 0, 1)]);
+	}
+
+	#[test]
+	fn test_percentiles() {
+		let d = Distribution::of(String::from("x"), (1..=100).rev().collect()).unwrap();
+		assert_eq!((d.count, d.min, d.p50, d.p90, d.p99, d.max), (100, 1, 50, 90, 99, 100));
+		let d = Distribution::of(String::from("x"), vec![7]).unwrap();
+		assert_eq!((d.p50, d.p99), (7, 7));
+		assert!(Distribution::of(String::from("x"), Vec::new()).is_none());
+
+		let report = parse(HISTORY, "", None);
+		let execution = report.latency.iter().find(|d| d.name == "execution").unwrap();
+		assert_eq!((execution.count, execution.min, execution.max), (2, 50, 150));
+		let ingress_to_link = report.latency.iter().find(|d| d.name == "ingress_to_link").unwrap();
+		assert_eq!((ingress_to_link.min, ingress_to_link.max), (100, 300));
+		let queue = &report.queue_sizes[0];
+		assert_eq!((queue.name.as_str(), queue.count, queue.min, queue.max), ("thread 1", 2, 1, 3));
+	}
+
+	#[test]
+	fn test_aborts() {
+		// Last snapshot of each thread and key, summed over codes and threads.
+		let report = parse("", CNT, Some(LATENCY));
+		let aborts: Vec<_> = report.aborts.iter().map(|a| (a.template, a.aborts, a.commits)).collect();
+		assert_eq!(aborts, vec![(0, 3, Some(6)), (3, 4, Some(0))]);
+		assert_eq!(report.aborts[0].rate, Some(3.0 / 9.0));
+		assert_eq!(report.aborts[1].rate, Some(1.0));
+
+		// No rate without latency.csv.
+		let report = parse("", CNT, None);
+		assert!(report.aborts.iter().all(|a| a.commits.is_none() && a.rate.is_none()));
+	}
+}
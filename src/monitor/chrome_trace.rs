@@ -0,0 +1,141 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use serde_json::{json, Value};
+
+use super::monitor::{Record, ThreadLogger, TraceStage};
+
+/*
+	History records as Chrome Trace Event JSON, opened by chrome://tracing or Perfetto.
+	- One track for each thread, named by role.
+	- Construction of a transaction as a span from dispatched_from_vnf to linked_to_tpg on the constructor track.
+	- Execution of an event as a span from fetching_value to accept or abort on its executor track.
+	- Spans of a transaction are linked by a flow with the request id.
+	Events are appended as records come, and the array is never closed, which the format allows.
+	Starts never ended, e.g. of executions discarded as stale, are dropped oldest first beyond MAX_OPEN.
+ */
+pub struct ChromeTrace {
+	out: BufWriter<File>,
+	// Span starts not ended yet.
+	open: HashMap<SpanKey, u64>,
+	// Starts in the order opened, to drop the oldest. Entries ended or opened again are skipped then.
+	opened: VecDeque<(SpanKey, u64)>,
+}
+
+// (tid, req_id, event idx) of a span.
+type SpanKey = (usize, u64, Option<i32>);
+
+// Span starts kept at most.
+const MAX_OPEN: usize = 1 << 16;
+
+impl ChromeTrace {
+	pub fn create(path: &str, loggers: &[ThreadLogger]) -> io::Result<Self> {
+		let mut out = BufWriter::new(File::create(path)?);
+		out.write_all(b"[\n")?;
+		let mut trace = ChromeTrace { out, open: HashMap::new(), opened: VecDeque::new() };
+		for (tid, tl) in loggers.iter().enumerate() {
+			trace.write(json!({
+				"name": "thread_name", "ph": "M", "pid": 0, "tid": tid,
+				"args": { "name": format!("{} {}", tl.role_name(), tid) },
+			}))?;
+		}
+		Ok(trace)
+	}
+
+	fn write(&mut self, event: Value) -> io::Result<()> {
+		serde_json::to_writer(&mut self.out, &event)?;
+		self.out.write_all(b",\n")
+	}
+
+	// Record logged by thread tid at ts.
+	pub fn record(&mut self, tid: usize, ts: u64, record: &Record) -> io::Result<()> {
+		match *record {
+			Record::Txn { req_id, stage, at, idx } => match stage {
+				TraceStage::DispatchedFromVnf | TraceStage::FetchingValue => {
+					self.open_span((tid, req_id, idx), at);
+				},
+				TraceStage::LinkedToTpg => {
+					if let Some(start) = self.open.remove(&(tid, req_id, None)) {
+						self.span(tid, "construct", start, at, json!({ "txn": req_id }))?;
+						self.flow("s", tid, req_id, start)?;
+					}
+				},
+				TraceStage::Accept | TraceStage::Abort => {
+					if let Some(start) = self.open.remove(&(tid, req_id, idx)) {
+						let name = format!("event {}", idx.unwrap_or(-1));
+						self.span(tid, &name, start, at, json!({ "txn": req_id, "outcome": stage.name() }))?;
+						self.flow("t", tid, req_id, start)?;
+					}
+				},
+				_ => {},
+			},
+			Record::Cascade { req_id, depth, fanout } => self.write(json!({
+				"name": "cascade", "ph": "i", "s": "t", "pid": 0, "tid": tid, "ts": us(ts),
+				"args": { "txn": req_id, "depth": depth, "fanout": fanout },
+			}))?,
+			Record::LocalQueueSize { tid, at, len } => self.write(json!({
+				"name": "local queue size", "ph": "C", "pid": 0, "tid": tid, "ts": us(at),
+				"args": { "len": len },
+			}))?,
+		}
+		Ok(())
+	}
+
+	fn open_span(&mut self, key: SpanKey, at: u64) {
+		self.open.insert(key, at);
+		self.opened.push_back((key, at));
+		while self.opened.len() > MAX_OPEN {
+			let (key, at) = self.opened.pop_front().unwrap();
+			if self.open.get(&key) == Some(&at) {
+				self.open.remove(&key);
+			}
+		}
+	}
+
+	fn span(&mut self, tid: usize, name: &str, start: u64, end: u64, args: Value) -> io::Result<()> {
+		self.write(json!({
+			"name": name, "cat": "txn", "ph": "X", "pid": 0, "tid": tid,
+			"ts": us(start), "dur": us(end.saturating_sub(start)), "args": args,
+		}))
+	}
+
+	fn flow(&mut self, ph: &str, tid: usize, req_id: u64, at: u64) -> io::Result<()> {
+		self.write(json!({
+			"name": "txn", "cat": "txn", "ph": ph, "id": req_id, "pid": 0, "tid": tid, "ts": us(at),
+		}))
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.out.flush()
+	}
+}
+
+// Trace timestamps are in microseconds.
+fn us(ns: u64) -> f64 {
+	ns as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_open_spans_bounded() {
+		let path = std::env::temp_dir().join(format!("db4nfv-trace-{}.json", std::process::id()));
+		let mut trace = ChromeTrace::create(path.to_str().unwrap(), &[]).unwrap();
+		let fetch = |req_id: u64| Record::Txn { req_id, stage: TraceStage::FetchingValue, at: req_id, idx: Some(0) };
+		// Never ended.
+		for req_id in 0..(MAX_OPEN + 10) as u64 {
+			trace.record(1, 0, &fetch(req_id)).unwrap();
+		}
+		assert_eq!(trace.open.len(), MAX_OPEN);
+		assert!(!trace.open.contains_key(&(1, 9, Some(0))));
+		assert!(trace.open.contains_key(&(1, 10, Some(0))));
+		// The newest still ends.
+		let last = MAX_OPEN as u64 + 9;
+		trace.record(1, 0, &Record::Txn { req_id: last, stage: TraceStage::Accept, at: last + 1, idx: Some(0) }).unwrap();
+		assert_eq!(trace.open.len(), MAX_OPEN - 1);
+		std::fs::remove_file(path).unwrap();
+	}
+}
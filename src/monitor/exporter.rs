@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::tpg::tpg::TPG;
+use crate::worker::construct_thread::GRACEFUL_SHUTDOWN;
+
+use super::latency;
+use super::monitor::{self, MONITOR};
+
+/*
+	Metrics in Prometheus text exposition format, served on GET /metrics at CONFIG.metrics_addr.
+	- Counters of each ThreadLogger, as db4nfv_<counter>_total labeled by thread. Dots in names become underscores.
+	- Abortions as db4nfv_txn_abort_total labeled by template and code.
+	- Gauges of queue depths, and those of monitor::gauges.
+	- Latency of each template and stage as summaries. See latency.
+	Connections are served one by one on this thread. Scrapes are rare, and rendering is cheap.
+ */
+pub fn exporter_thread() {
+	let addr = match CONFIG.read().unwrap().metrics_addr.clone() {
+		Some(addr) => addr,
+		None => return,
+	};
+	let listener = match TcpListener::bind(&addr) {
+		Ok(listener) => listener,
+		Err(err) => {
+			eprintln!("Failed to bind metrics exporter on {}: {}", addr, err);
+			return;
+		}
+	};
+	// Polled, to notice shutdown.
+	listener.set_nonblocking(true).expect("Failed to set metrics listener nonblocking.");
+	loop {
+		if unsafe { GRACEFUL_SHUTDOWN } {
+			println!("Metrics exporter shutdown. ");
+			break;
+		}
+		match listener.accept() {
+			Ok((stream, _)) => {
+				if let Err(err) = serve(stream) {
+					eprintln!("Failed to serve metrics: {}", err);
+				}
+			},
+			Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+			Err(err) => eprintln!("Failed to accept metrics connection: {}", err),
+		}
+	}
+}
+
+fn serve(stream: TcpStream) -> std::io::Result<()> {
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+	let mut reader = BufReader::new(&stream);
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line)?;
+	// Skip headers.
+	let mut line = String::new();
+	while reader.read_line(&mut line)? > 2 {
+		line.clear();
+	}
+	let mut parts = request_line.split_whitespace();
+	let (status, body) = match (parts.next(), parts.next()) {
+		(Some("GET"), Some("/metrics")) => ("200 OK", render()),
+		_ => ("404 Not Found", String::from("Not found. Try GET /metrics.\n")),
+	};
+	let mut stream = &stream;
+	write!(stream,
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status, body.len(), body
+	)?;
+	stream.flush()
+}
+
+// Metric family: type, then samples of name suffix, labels and value. Suffixes are _sum and _count of summaries.
+type Families = BTreeMap<String, (&'static str, Vec<(&'static str, String, i64)>)>;
+
+fn sample(families: &mut Families, name: &str, kind: &'static str, labels: String, value: i64) {
+	sample_suffixed(families, name, "", kind, labels, value);
+}
+
+fn sample_suffixed(families: &mut Families, name: &str, suffix: &'static str, kind: &'static str, labels: String, value: i64) {
+	families.entry(format!("db4nfv_{}", name.replace('.', "_")))
+		.or_insert_with(|| (kind, Vec::new()))
+		.1.push((suffix, labels, value));
+}
+
+pub fn render() -> String {
+	let mut families = Families::new();
+	if let Some(monitors) = MONITOR.get() {
+		monitors.iter().enumerate().for_each(|(tid, tl)| {
+			for (k, v) in tl.counters() {
+				sample(&mut families, &format!("{}_total", k.trim_end_matches('.')), "counter",
+					format!("tid=\"{}\",role=\"{}\"", tid, tl.role_name()), v);
+			}
+			for ((type_idx, code), v) in tl.abort_counters() {
+				sample(&mut families, "txn_abort_total", "counter",
+					format!("tid=\"{}\",role=\"{}\",template=\"{}\",code=\"{}\"", tid, tl.role_name(), type_idx, code), v);
+			}
+		});
+	}
+	if let Some(tpg) = TPG.get() {
+		tpg.ready_queue_in.depths().iter().enumerate().for_each(|(priority, d)| {
+			sample(&mut families, "ready_queue_depth", "gauge", format!("priority=\"{}\"", priority), *d as i64);
+		});
+		tpg.worker_depths().iter().enumerate().for_each(|(worker, (local, inbox))| {
+			sample(&mut families, "local_queue_depth", "gauge", format!("worker=\"{}\"", worker), *local as i64);
+			sample(&mut families, "inbox_depth", "gauge", format!("worker=\"{}\"", worker), *inbox as i64);
+		});
+	}
+	monitor::gauges().into_iter().for_each(|(k, v)| sample(&mut families, &k, "gauge", String::new(), v));
+
+	// Latency histograms as summaries.
+	latency::histograms().for_each(|(type_idx, stage, h)| {
+		let labels = format!("template=\"{}\",stage=\"{}\"", type_idx, stage.name());
+		latency::QUANTILES.iter().for_each(|q| {
+			sample(&mut families, "latency_ns", "summary", format!("{},quantile=\"{}\"", labels, q), h.quantile(*q) as i64);
+		});
+		sample_suffixed(&mut families, "latency_ns", "_sum", "summary", labels.clone(), h.sum() as i64);
+		sample_suffixed(&mut families, "latency_ns", "_count", "summary", labels, h.count() as i64);
+	});
+
+	let mut out = String::new();
+	families.iter().for_each(|(name, (kind, samples))| {
+		let _ = writeln!(out, "# TYPE {} {}", name, kind);
+		samples.iter().for_each(|(suffix, labels, v)| {
+			if labels.is_empty() {
+				let _ = writeln!(out, "{}{} {}", name, suffix, v);
+			} else {
+				let _ = writeln!(out, "{}{}{{{}}} {}", name, suffix, labels, v);
+			}
+		});
+	});
+	out
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::ds::transactions::TXN_TEMPLATES;
+
+// Stages of a transaction timed. Events are timed on their own, so event stages count once per event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+	IngressToLink,
+	LinkToReady,
+	ReadyToClaim,
+	Execution,
+	IngressToCommit,
+}
+
+pub const STAGES: [Stage; 5] = [Stage::IngressToLink, Stage::LinkToReady, Stage::ReadyToClaim, Stage::Execution, Stage::IngressToCommit];
+
+impl Stage {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Stage::IngressToLink => "ingress_to_link",
+			Stage::LinkToReady => "link_to_ready",
+			Stage::ReadyToClaim => "ready_to_claim",
+			Stage::Execution => "execution",
+			Stage::IngressToCommit => "ingress_to_commit",
+		}
+	}
+}
+
+// Sub buckets of each power of 2. Values are kept within 1/SUB_BUCKETS of precision.
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/*
+	HDR-style histogram of nanoseconds. Log-linear buckets of atomic counts, so any thread records without locks.
+	Values below SUB_BUCKETS are exact.
+ */
+pub struct Histogram {
+	buckets: Vec<AtomicU64>,
+	count: AtomicU64,
+	sum: AtomicU64,
+	max: AtomicU64,
+}
+
+impl Histogram {
+	pub fn new() -> Self {
+		Histogram {
+			buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+			count: AtomicU64::new(0),
+			sum: AtomicU64::new(0),
+			max: AtomicU64::new(0),
+		}
+	}
+
+	fn bucket_of(v: u64) -> usize {
+		if v < SUB_BUCKETS as u64 {
+			return v as usize
+		}
+		// Position of the highest bit, and the SUB_BITS bits below it.
+		let exp = 63 - v.leading_zeros();
+		let sub = (v >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+		(exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+	}
+
+	// Highest value of a bucket.
+	fn value_of(bucket: usize) -> u64 {
+		if bucket < SUB_BUCKETS {
+			return bucket as u64
+		}
+		let exp = (bucket / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+		let sub = (bucket % SUB_BUCKETS) as u64;
+		((SUB_BUCKETS as u64 + sub) << (exp - SUB_BITS)) + ((1u64 << (exp - SUB_BITS)) - 1)
+	}
+
+	pub fn record(&self, v: u64) {
+		self.buckets[Self::bucket_of(v)].fetch_add(1, Ordering::Relaxed);
+		self.count.fetch_add(1, Ordering::Relaxed);
+		self.sum.fetch_add(v, Ordering::Relaxed);
+		self.max.fetch_max(v, Ordering::Relaxed);
+	}
+
+	pub fn count(&self) -> u64 {
+		self.count.load(Ordering::Relaxed)
+	}
+
+	pub fn sum(&self) -> u64 {
+		self.sum.load(Ordering::Relaxed)
+	}
+
+	pub fn max(&self) -> u64 {
+		self.max.load(Ordering::Relaxed)
+	}
+
+	// Value at quantile q in [0, 1]. Upper bound of its bucket, capped by max. 0 if empty.
+	pub fn quantile(&self, q: f64) -> u64 {
+		let count = self.count();
+		if count == 0 {
+			return 0
+		}
+		let rank = ((q * count as f64).ceil() as u64).clamp(1, count);
+		let mut seen = 0;
+		for (bucket, c) in self.buckets.iter().enumerate() {
+			seen += c.load(Ordering::Relaxed);
+			if seen >= rank {
+				return Self::value_of(bucket).min(self.max())
+			}
+		}
+		self.max()
+	}
+}
+
+// Quantiles reported.
+pub const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+// One histogram of each stage, for each template.
+static LATENCY: OnceCell<Vec<Vec<Histogram>>> = OnceCell::new();
+
+// After templates are loaded.
+pub fn init() {
+	let templates = TXN_TEMPLATES.get().map_or(0, |t| t.len());
+	let _ = LATENCY.set((0..templates).map(|_| STAGES.iter().map(|_| Histogram::new()).collect()).collect());
+}
+
+pub fn record(type_idx: u16, stage: Stage, ns: u64) {
+	if let Some(h) = LATENCY.get().and_then(|l| l.get(type_idx as usize)) {
+		h[stage as usize].record(ns);
+	}
+}
+
+// Histograms of each template and stage.
+pub fn histograms() -> impl Iterator<Item = (usize, Stage, &'static Histogram)> {
+	LATENCY.get().into_iter().flatten().enumerate().flat_map(|(type_idx, hs)| {
+		STAGES.iter().zip(hs.iter()).map(move |(stage, h)| (type_idx, *stage, h))
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_quantiles() {
+		let h = Histogram::new();
+		(1..=10000u64).for_each(|v| h.record(v * 1000));
+		assert_eq!(h.count(), 10000);
+		assert_eq!(h.max(), 10_000_000);
+		// Within the precision of buckets.
+		let p99 = h.quantile(0.99) as f64;
+		assert!((p99 - 9_900_000.0).abs() / 9_900_000.0 < 1.0 / SUB_BUCKETS as f64);
+		assert_eq!(h.quantile(1.0), 10_000_000);
+	}
+}
@@ -1 +1,6 @@
-pub mod monitor;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod monitor;
+pub mod exporter;
+pub mod latency;
+pub mod chrome_trace;
+pub mod analysis;
\ No newline at end of file
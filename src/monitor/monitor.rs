@@ -1,17 +1,120 @@
-use std::{cell::UnsafeCell, collections::HashMap, fs::{self, File}, io::Write, path::Path, sync::atomic::Ordering, thread, time::{Duration, SystemTime}};
+use std::{cell::UnsafeCell, collections::hash_map::DefaultHasher, fmt, hash::{Hash, Hasher}, collections::HashMap, fs::{self, File}, io::Write, path::Path, sync::{atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering}, Mutex}, thread, time::Duration};
 
 use once_cell::sync::OnceCell;
 
-use crate::{config::CONFIG, worker::construct_thread::GRACEFUL_SHUTDOWN};
+use super::chrome_trace::ChromeTrace;
+use super::latency;
+
+use crate::{config::CONFIG, database::simpledb::DB, tpg::{ev_node::{Cascade, LIVE_EV_NODES}, tpg::TPG, txn_node::{TxnNode, LIVE_TXN_NODES, LONGEST_CHAIN, WAITING_TXNS}}, utils, worker::{construct_thread::GRACEFUL_SHUTDOWN, watchdog::STUCK_TXNS}};
 
 pub struct Metrics {
 	pub ts: u64,
-	pub content: String,
+	pub record: Record,
+}
+
+/*
+	History records. Kept structured, and formatted into history.csv lines by the monitor thread,
+	so threads traced pay no formatting.
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum Record {
+	// A transaction reached a stage at some time. By one of its events, if idx is some.
+	Txn { req_id: u64, stage: TraceStage, at: u64, idx: Option<i32> },
+	Cascade { req_id: u64, depth: usize, fanout: usize },
+	LocalQueueSize { tid: usize, at: u64, len: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceStage {
+	DispatchedFromVnf,
+	SortingDone,
+	LinkedToTpg,
+	ReadyToBeFetched,
+	EvClaimed,
+	FetchingValue,
+	FetchValueDone,
+	ExecutionDone,
+	Accept,
+	Abort,
 }
 
+impl TraceStage {
+	pub fn name(&self) -> &'static str {
+		match self {
+			TraceStage::DispatchedFromVnf => "dispatched_from_vnf",
+			TraceStage::SortingDone => "sorting_done",
+			TraceStage::LinkedToTpg => "linked_to_tpg",
+			TraceStage::ReadyToBeFetched => "ready_to_be_fetched",
+			TraceStage::EvClaimed => "ev_claimed",
+			TraceStage::FetchingValue => "fetching_value",
+			TraceStage::FetchValueDone => "fetch_value_done",
+			TraceStage::ExecutionDone => "execution_done",
+			TraceStage::Accept => "accept",
+			TraceStage::Abort => "abort",
+		}
+	}
+}
+
+impl fmt::Display for Record {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Record::Txn { req_id, stage, at, idx: Some(idx) } => write!(f, "{},{},{},{}", req_id, stage.name(), at, idx),
+			Record::Txn { req_id, stage, at, idx: None } => write!(f, "{},{},{}", req_id, stage.name(), at),
+			Record::Cascade { req_id, depth, fanout } => write!(f, "{},cascade,{},{}", req_id, depth, fanout),
+			Record::LocalQueueSize { tid, at, len } => write!(f, "{},thread local queue size,{},{}", tid, at, len),
+		}
+	}
+}
+
+/*
+	Bounded ring of history records. Single producer, the thread owning the logger, and single consumer, the monitor thread.
+	- Producer writes the slot at head, then publishes it by advancing head.
+	- Consumer takes the slots from tail to head, then frees them by advancing tail.
+	- Records logged when the ring is full are dropped, as the monitor fell behind. Counted as monitor.dropped.
+ */
 struct History {
-	logs: Vec<Metrics>,
-	written: usize,
+	logs: Vec<UnsafeCell<Option<Metrics>>>,
+	head: AtomicUsize,
+	tail: AtomicUsize,
+}
+
+// Slots are only touched by one side at a time, as head and tail decide.
+unsafe impl Sync for History {}
+
+impl History {
+	fn new() -> Self {
+		let cap = CONFIG.read().unwrap().monitor_buffer_size.max(1);
+		History {
+			logs: (0..cap).map(|_| UnsafeCell::new(None)).collect(),
+			head: AtomicUsize::new(0),
+			tail: AtomicUsize::new(0),
+		}
+	}
+
+	// Producer only. False if full.
+	fn push(&self, m: Metrics) -> bool {
+		let head = self.head.load(Ordering::Relaxed);
+		if head - self.tail.load(Ordering::Acquire) == self.logs.len() {
+			return false
+		}
+		// Freed by consumer, and not visible to it till head advances.
+		unsafe { *self.logs[head % self.logs.len()].get() = Some(m) };
+		self.head.store(head + 1, Ordering::Release);
+		true
+	}
+
+	// Consumer only.
+	fn drain(&self, mut f: impl FnMut(Metrics)) {
+		let tail = self.tail.load(Ordering::Relaxed);
+		let head = self.head.load(Ordering::Acquire);
+		for i in tail..head {
+			// Published by producer, and not written again till tail advances.
+			if let Some(m) = unsafe { (*self.logs[i % self.logs.len()].get()).take() } {
+				f(m);
+			}
+		}
+		self.tail.store(head, Ordering::Release);
+	}
 }
 
 trait Report {
@@ -20,57 +123,213 @@ trait Report {
 
 pub struct ThreadLogger {
 	role: ThreadRole,
-	history: UnsafeCell<History>,
-	counter: HashMap<String, UnsafeCell<i32>>,
+	history: History,
+	counter: HashMap<String, AtomicI64>,
+	// Abortion count of each (txn template, abort code). Codes are application-defined, so entries are added on the fly.
+	abort_counter: Mutex<HashMap<(u16, u32), i64>>,
 }
 
-unsafe impl Sync for ThreadLogger {}
-
 impl ThreadLogger {
 	pub fn new_worker() -> Self{
 		ThreadLogger{
 			role: ThreadRole::EXECUTOR,
-			history: History{ logs: Vec::with_capacity(100000), written: 0}.into(),
+			history: History::new(),
 			counter: HashMap::from([
-				(String::from("evnode.accept"), UnsafeCell::new(0)),
-				(String::from("evnode.abort"), UnsafeCell::new(0)),
+				(String::from("evnode.accept"), AtomicI64::new(0)),
+				(String::from("monitor.dropped"), AtomicI64::new(0)),
+				(String::from("evnode.abort"), AtomicI64::new(0)),
+				(String::from("evnode.stolen"), AtomicI64::new(0)),
+				(String::from("evnode.forwarded"), AtomicI64::new(0)),
+				(String::from("thread.idle_us"), AtomicI64::new(0)),
+				(String::from("txn.cascade.reset"), AtomicI64::new(0)),
+				(String::from("txn.cascade.pessimistic"), AtomicI64::new(0)),
+				(String::from("race.claim_lost"), AtomicI64::new(0)),
+				(String::from("race.reset_in_queue"), AtomicI64::new(0)),
+				(String::from("race.duplicate_in_queue"), AtomicI64::new(0)),
+				(String::from("race.stale_result"), AtomicI64::new(0)),
+				(String::from("race.rescheduled"), AtomicI64::new(0)),
 			]),
+			abort_counter: Mutex::new(HashMap::new()),
 		}	
 	}
 	pub fn new_constructor() -> Self{
 		ThreadLogger{
 			role: ThreadRole::CONSTRUCTOR,
-			history: History{ logs: Vec::with_capacity(100000), written: 0}.into(),
+			history: History::new(),
+			counter: HashMap::from([
+				(String::from("evnode.let_occupy"), AtomicI64::new(0)),
+				(String::from("monitor.dropped"), AtomicI64::new(0)),
+				(String::from("evnode.enqueue"), AtomicI64::new(0)),
+				(String::from("rare_condition.claimed_when_counting."), AtomicI64::new(0)),
+				(String::from("evnode.dynamic_key_invalid"), AtomicI64::new(0)),
+				(String::from("txn.held"), AtomicI64::new(0)),
+				(String::from("txn.retry"), AtomicI64::new(0)),
+				(String::from("txn.timeout"), AtomicI64::new(0)),
+				(String::from("thread.idle_us"), AtomicI64::new(0)),
+				(String::from("txn.cascade.reset"), AtomicI64::new(0)),
+				(String::from("txn.cascade.pessimistic"), AtomicI64::new(0)),
+			]),
+			abort_counter: Mutex::new(HashMap::new()),
+		}	
+	}
+	// Shared by the monitor and the watchdog. Only the watchdog logs history.
+	pub fn new_monitor() -> Self{
+		ThreadLogger{
+			role: ThreadRole::MONITOR,
+			history: History::new(),
 			counter: HashMap::from([
-				(String::from("evnode.let_occupy"), UnsafeCell::new(0)),
-				(String::from("evnode.enqueue"), UnsafeCell::new(0)),
-				(String::from("rare_condition.claimed_when_counting."), UnsafeCell::new(0)),
+				(String::from("monitor.dropped"), AtomicI64::new(0)),
+				(String::from("watchdog.stuck"), AtomicI64::new(0)),
+				(String::from("watchdog.aborted"), AtomicI64::new(0)),
+				(String::from("txn.cascade.reset"), AtomicI64::new(0)),
+				(String::from("txn.cascade.pessimistic"), AtomicI64::new(0)),
 			]),
+			abort_counter: Mutex::new(HashMap::new()),
 		}	
 	}
 	pub fn new_vnf() -> Self{
 		ThreadLogger{
 			role: ThreadRole::VNF,
-			history: History{ logs: Vec::with_capacity(100000), written: 0}.into(),
-			counter: HashMap::new()
+			history: History::new(),
+			counter: HashMap::from([
+				(String::from("monitor.dropped"), AtomicI64::new(0)),
+			]),
+			abort_counter: Mutex::new(HashMap::new()),
 		}	
 	}
+	// Only by the thread owning this logger.
 	pub fn log(&self, m: Metrics) {
-		unsafe {
-            let history = &mut *self.history.get();
-            history.logs.push(m);
-        }
+		if !self.history.push(m) {
+			self.inc("monitor.dropped");
+		}
+	}
+	pub fn inc_abort(&self, type_idx: u16, code: u32) {
+		*self.abort_counter.lock().unwrap().entry((type_idx, code)).or_insert(0) += 1;
 	}
 	pub fn inc(&self, entry: &str) {
-		unsafe {
-            let cnt = &mut *self.counter[entry].get();
-            *cnt += 1;
-        }
+		self.add(entry, 1);
+	}
+	pub(super) fn counters(&self) -> Vec<(String, i64)> {
+		self.counter.iter().map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed))).collect()
+	}
+	pub(super) fn abort_counters(&self) -> Vec<((u16, u32), i64)> {
+		self.abort_counter.lock().unwrap().iter().map(|(k, v)| (*k, *v)).collect()
+	}
+	pub(super) fn role_name(&self) -> &'static str {
+		match self.role {
+			ThreadRole::EXECUTOR => "executor",
+			ThreadRole::CONSTRUCTOR => "constructor",
+			ThreadRole::MONITOR => "monitor",
+			ThreadRole::VNF => "vnf",
+		}
+	}
+	pub fn add(&self, entry: &str, n: i64) {
+		self.counter[entry].fetch_add(n, Ordering::Relaxed);
 	}
 }
 
 pub static MONITOR: OnceCell<Vec<ThreadLogger>> = OnceCell::new();
 
+// Count an abortion that takes effect, with how far it cascades. Called by the thread tid.
+pub fn record_abort(tid: usize, tn: &TxnNode, code: u32, cascade: Cascade) {
+	let tl = &MONITOR.get().unwrap()[tid];
+	tl.inc_abort(tn.type_idx, code);
+	tl.add("txn.cascade.reset", cascade.fanout as i64);
+	tl.add("txn.cascade.pessimistic", cascade.pessimistic as i64);
+	if tn.traced {
+		tl.log(Metrics{
+			ts: utils::current_time_ns(),
+			record: Record::Cascade { req_id: tn.txn_req_id, depth: cascade.depth, fanout: cascade.fanout },
+		});
+	}
+}
+
+// Log a stage of a traced transaction, reached now. Called by the thread tid.
+pub fn trace(tid: usize, tn: &TxnNode, stage: TraceStage, idx: Option<i32>) {
+	if tn.traced {
+		trace_at(tid, tn, stage, utils::current_time_ns(), idx);
+	}
+}
+
+pub fn trace_at(tid: usize, tn: &TxnNode, stage: TraceStage, at: u64, idx: Option<i32>) {
+	if tn.traced {
+		MONITOR.get().unwrap()[tid].log(Metrics{
+			ts: utils::current_time_ns(),
+			record: Record::Txn { req_id: tn.txn_req_id, stage, at, idx },
+		});
+	}
+}
+
+// Longest chain of the last period. Taken by the monitor thread each second.
+static LONGEST_CHAIN_SAMPLED: AtomicI64 = AtomicI64::new(0);
+
+/*
+	Gauges of TPG and version store health. Written to cnt.csv by the monitor thread, and served by the exporter.
+	Cascaded resets are counted by threads, as txn.cascade.reset.
+ */
+pub fn gauges() -> Vec<(String, i64)> {
+	let mut gauges: Vec<(&str, i64)> = vec![
+		("tpg.txn_nodes", LIVE_TXN_NODES.load()),
+		("tpg.ev_nodes", LIVE_EV_NODES.load()),
+		("tpg.waiting_txns", WAITING_TXNS.load()),
+		("tpg.longest_chain", LONGEST_CHAIN_SAMPLED.load(Ordering::Relaxed)),
+		("watchdog.stuck_txns", STUCK_TXNS.load()),
+	];
+	if let Some(tpg) = TPG.get() {
+		gauges.push(("queue.ready", tpg.ready_queue_in.depths().iter().sum::<usize>() as i64));
+		gauges.push(("tpg.pessimistic_states", tpg.pessimistic_states.read().unwrap().len() as i64));
+	}
+	let stats = DB.get().map(|db| db.stats());
+	if let Some(stats) = &stats {
+		gauges.extend([
+			("db.versions", stats.versions as i64),
+			("db.versions_capacity", stats.capacity as i64),
+			("db.ringbuf_full", stats.full_events as i64),
+		]);
+	}
+	// States by versions held, named by the most of each bucket.
+	let per_key = stats.iter().flat_map(|stats| stats.per_key.iter().enumerate())
+		.map(|(b, &n)| (format!("db.versions_per_key.upto_{}", (1usize << b) - 1), n as i64));
+	gauges.into_iter().map(|(k, v)| (k.to_string(), v)).chain(per_key).collect()
+}
+
+// Counters of each thread and gauges now, as lines of cnt.csv.
+pub fn counters_csv() -> String {
+	let mut out = String::new();
+	MONITOR.get().unwrap().iter().enumerate().for_each(|(tid, tl)| {
+		let mut counters = tl.counters();
+		counters.sort();
+		counters.iter().for_each(|(k, v)| out += &format!("{},{:?},{},{}\n", tid, tl.role, k, v));
+		let mut aborts = tl.abort_counters();
+		aborts.sort();
+		aborts.iter().for_each(|((type_idx, code), v)| out += &format!("{},{:?},txn.abort.{}.{},{}\n", tid, tl.role, type_idx, code, v));
+	});
+	let tid = MONITOR.get().unwrap().len() - 1;
+	gauges().iter().for_each(|(k, v)| out += &format!("{},{:?},{},{}\n", tid, ThreadRole::MONITOR, k, v));
+	out
+}
+
+// Transactions created so far, for sampling by count.
+static SAMPLE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/*
+	Whether a new transaction is traced. Decided once when its TxnNode is created.
+	One in trace_sample_n transactions, by count, or by hash of the request id to pick the same ones across runs.
+ */
+pub fn sampled(req_id: u64) -> bool {
+	let config = CONFIG.read().unwrap();
+	if !config.monitor_enabled || config.trace_sample_n == 0 {
+		return false
+	}
+	if config.trace_sample_by_hash {
+		let mut hasher = DefaultHasher::new();
+		req_id.hash(&mut hasher);
+		hasher.finish().is_multiple_of(config.trace_sample_n)
+	} else {
+		SAMPLE_SEQ.fetch_add(1, Ordering::Relaxed).is_multiple_of(config.trace_sample_n)
+	}
+}
+
 #[derive(Debug)]
 enum ThreadRole{
 	EXECUTOR,
@@ -104,12 +363,15 @@ pub fn init() {
 		.into_iter().for_each(
 			|_| monitors.push(ThreadLogger::new_worker())
 		);
+	monitors.push(ThreadLogger::new_monitor()); // Monitor thread, tid worker_thread_ends.
 	let _ = MONITOR.set(monitors);
 }
 
 pub fn monitor_thread(tid: usize){
 	let his_file_path = format!("{}/history.csv", CONFIG.read().unwrap().log_dir);
 	let cnt_file_path = format!("{}/cnt.csv", CONFIG.read().unwrap().log_dir);
+	let latency_file_path = format!("{}/latency.csv", CONFIG.read().unwrap().log_dir);
+	let trace_file_path = format!("{}/trace.json", CONFIG.read().unwrap().log_dir);
 
 	// Open log file for writing.
     let mut his_log = match File::create(&his_file_path) {
@@ -128,38 +390,85 @@ pub fn monitor_thread(tid: usize){
         }
     };
 
+	let mut trace = if CONFIG.read().unwrap().trace_export {
+		match ChromeTrace::create(&trace_file_path, MONITOR.get().unwrap()) {
+			Ok(trace) => Some(trace),
+			Err(err) => {
+				eprintln!("Failed to create trace file {}: {}", trace_file_path, err);
+				None
+			}
+		}
+	} else {
+		None
+	};
+
 	// Collect data and write to log file every 1 second.
     loop {
         if unsafe { GRACEFUL_SHUTDOWN } {
             println!("Monitor thread shutdown. ");
             break;
         }
-		dump_log(&mut his_log, &mut cnt_log);
+		LONGEST_CHAIN_SAMPLED.store(LONGEST_CHAIN.swap(0) as i64, Ordering::Relaxed);
+		dump_log(&mut his_log, &mut cnt_log, trace.as_mut());
+		gauges().iter().for_each(|(k, v)| {
+			if let Err(err) = writeln!(cnt_log, "{},{:?},{},{}", tid, ThreadRole::MONITOR, k, v) {
+				eprintln!("Failed to write to count log file {}", err);
+			}
+		});
+		if let Some(Err(err)) = trace.as_mut().map(|t| t.flush()) {
+			eprintln!("Failed to write to trace file {}", err);
+		}
+		dump_latency(&latency_file_path);
 
         thread::sleep(Duration::from_secs(1));
     }
 }
 
-pub fn dump_log(history_l: &mut File, cnt_l: &mut File){
+// Percentiles so far of each template and stage, in ns. Rewritten each time.
+pub fn dump_latency(path: &str) {
+	let mut content = String::from("template,stage,count,mean,p50,p90,p99,p999,max\n");
+	latency::histograms().filter(|(_, _, h)| h.count() > 0).for_each(|(type_idx, stage, h)| {
+		content += &format!("{},{},{},{},", type_idx, stage.name(), h.count(), h.sum() / h.count());
+		content += &latency::QUANTILES.iter().map(|q| h.quantile(*q).to_string()).collect::<Vec<_>>().join(",");
+		content += &format!(",{}\n", h.max());
+	});
+	if let Err(err) = fs::write(path, content) {
+		eprintln!("Failed to write latency file {}: {}", path, err);
+	}
+}
+
+pub fn dump_log(history_l: &mut File, cnt_l: &mut File, mut trace: Option<&mut ChromeTrace>){
 	// Write data to log file.
 	MONITOR.get().unwrap().iter().enumerate().for_each(
 		|(tid, tl)|{
 			tl.counter.iter().for_each(|(k, v)|{
 				if let Err(err) = cnt_l.write_fmt(format_args!(
 					"{},{:?},{},{}\n", 
-					tid,tl.role,k,unsafe{*v.get()}
+					tid,tl.role,k,v.load(Ordering::Relaxed)
 				)) {
 					eprintln!("Failed to write to count log file {}", err);
 				}
 			});
-			let h =  unsafe { &*tl.history.get() };
-				h.logs[h.written..].iter().for_each(|m|{
+			tl.abort_counter.lock().unwrap().iter().for_each(|((type_idx, code), v)|{
+				if let Err(err) = cnt_l.write_fmt(format_args!(
+					"{},{:?},txn.abort.{}.{},{}\n", 
+					tid,tl.role,type_idx,code,v
+				)) {
+					eprintln!("Failed to write to count log file {}", err);
+				}
+			});
+			tl.history.drain(|m|{
 				if let Err(err) = history_l.write_fmt(format_args!(
 					"{},{:?},{},{}\n", 
-					tid,tl.role,m.ts,m.content
+					tid,tl.role,m.ts,m.record
 				)){
 					eprintln!("Failed to write to history log file {}", err);
 				}
+				if let Some(trace) = trace.as_mut() {
+					if let Err(err) = trace.record(tid, m.ts, &m.record) {
+						eprintln!("Failed to write to trace file {}", err);
+					}
+				}
 			})
 		}
 	);
@@ -0,0 +1,100 @@
+use std::fs;
+use serde::{Serialize, Deserialize};
+use lazy_static::lazy_static;
+use core_affinity::CoreId;
+
+use crate::config::CONFIG;
+
+/*
+    Placement of threads on cores, and of cores on NUMA nodes.
+    Cores are CPU ids as in /sys/devices/system/cpu. Roles not mapped fall back to the core of their thread id,
+    which does not care about sockets.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CoreMap {
+    #[serde(default)]
+    pub constructor: Option<usize>,
+    // Cores the VNF runtime starts on. Its threads inherit them unless the runtime binds them itself.
+    #[serde(default)]
+    pub vnf: Vec<usize>,
+    // One for each executor thread.
+    #[serde(default)]
+    pub executors: Vec<usize>,
+    #[serde(default)]
+    pub monitor: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Constructor,
+    Vnf,
+    Executor(usize), // Index among executor threads.
+    Monitor,
+}
+
+lazy_static! {
+    static ref AVAILABLE_CPUS: Vec<CoreId> = {
+        core_affinity::get_core_ids()
+            .expect("Failed to get core IDs")
+    };
+}
+
+// Cores of a role. Empty to leave it unbound.
+pub fn cores_of(role: Role) -> Vec<usize> {
+    let config = CONFIG.read().unwrap();
+    let map = &config.core_map;
+    match role {
+        Role::Constructor => map.constructor.or_else(|| AVAILABLE_CPUS.first().map(|c| c.id)).into_iter().collect(),
+        Role::Vnf => map.vnf.clone(),
+        // Unbound if there are fewer cores than threads.
+        Role::Executor(idx) => map.executors.get(idx).copied()
+            .or_else(|| AVAILABLE_CPUS.get(config.vnf_threads_num as usize + 1 + idx).map(|c| c.id))
+            .into_iter().collect(),
+        Role::Monitor => map.monitor.into_iter().collect(),
+    }
+}
+
+// Bind the current thread to the cores of its role.
+pub fn bind(role: Role) {
+    let cores = cores_of(role);
+    let res = match cores.as_slice() {
+        [] => true,
+        [core] => core_affinity::set_for_current(CoreId { id: *core }),
+        _ => affinity::set_thread_affinity(&cores).is_ok(),
+    };
+    if !res {
+        panic!("Binding {:?} to {:?} failed.", role, cores)
+    }
+}
+
+// NUMA node of a cpu. From the nodeN entry sysfs keeps in the cpu directory. 0 if there is none, as on single-node machines.
+pub fn node_of_cpu(cpu: usize) -> usize {
+    fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix("node")?.parse().ok())
+        .next()
+        .unwrap_or(0)
+}
+
+// NUMA node of each of the executor threads. Node 0 for those unbound.
+pub fn executor_nodes(workers: usize) -> Vec<usize> {
+    (0..workers)
+        .map(|idx| cores_of(Role::Executor(idx)).first().map_or(0, |&core| node_of_cpu(core)))
+        .collect()
+}
+
+// Check the core map against the cores available. Panic on cores that do not exist, or executors left unmapped.
+pub fn check() {
+    let config = CONFIG.read().unwrap();
+    let map = &config.core_map;
+    let mapped = map.constructor.iter().chain(map.vnf.iter()).chain(map.executors.iter()).chain(map.monitor.iter());
+    for core in mapped {
+        if !AVAILABLE_CPUS.iter().any(|c| c.id == *core) {
+            panic!("Core {} in core_map is not available.", core);
+        }
+    }
+    if !map.executors.is_empty() && map.executors.len() != config.worker_threads_num as usize {
+        panic!("core_map maps {} executors, but worker_threads_num is {}.", map.executors.len(), config.worker_threads_num);
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::Arc;
+
+use crate::config::CONFIG;
+use crate::utils;
+
+use super::ev_node::EvNode;
+use super::tpg::TPG;
+use super::txn_node::{TxnNode, TxnStatus};
+
+/*
+	Dump of the live TPG in Graphviz DOT. Render with `dot -Tsvg tpg.dot -o tpg.svg`.
+	- One cluster for each transaction, with its status, and one node for each of its events.
+	- read_from edges between events, labeled by the state read. Bold red if not fulfilled yet, which keeps the son WAITING.
+	- cover edges between transactions, dashed, labeled by the state written.
+	- Links seen from one end only, e.g. read_by without the matching read_from, are drawn orange.
+	Transactions are those reached by Tpg::live_txns.
+	Locks held by others are skipped rather than waited for, so it's safe to call from a panic hook. The dump may miss parts then.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct DotFilter {
+	// Only transactions reading or writing this state.
+	pub key: Option<String>,
+	// Only this transaction and its uncommitted ancestors.
+	pub txn: Option<u64>,
+}
+
+fn id<T>(p: *const T) -> String {
+	format!("n{:x}", p as usize)
+}
+
+fn events(tn: &TxnNode) -> Vec<Arc<EvNode>> {
+	tn.ev_nodes.try_read().map(|e| e.clone()).unwrap_or_default()
+}
+
+// Transactions kept by the filter.
+fn filter(txns: Vec<Arc<TxnNode>>, f: &DotFilter) -> Vec<Arc<TxnNode>> {
+	let mut txns = txns;
+	if let Some(key) = &f.key {
+		txns.retain(|tn| events(tn).iter().any(|e| e.reads.contains(key) || e.writes.contains(key)));
+	}
+	if let Some(req_id) = f.txn {
+		// Ancestors, through read_from and cover.
+		let by_ptr: HashMap<_, _> = txns.iter().map(|tn| (Arc::as_ptr(tn), tn.clone())).collect();
+		let mut keep = HashSet::new();
+		let mut stack: Vec<Arc<TxnNode>> = txns.iter().filter(|tn| tn.txn_req_id == req_id).cloned().collect();
+		while let Some(tn) = stack.pop() {
+			if !keep.insert(Arc::as_ptr(&tn)) {
+				continue
+			}
+			let mut parents: Vec<Arc<TxnNode>> = Vec::new();
+			if let Ok(l) = tn.read_from.try_read() { parents.extend(l.iter().flatten().cloned()) }
+			if let Ok(l) = tn.cover.try_read() { parents.extend(l.values().flatten().cloned()) }
+			stack.extend(parents.into_iter()
+				.filter(|p| p.status.load() != TxnStatus::COMMITED && by_ptr.contains_key(&Arc::as_ptr(p))));
+		}
+		txns.retain(|tn| keep.contains(&Arc::as_ptr(tn)));
+	}
+	txns
+}
+
+pub fn render(f: &DotFilter) -> String {
+	let txns = filter(TPG.get().map_or(Vec::new(), |tpg| tpg.live_txns()), f);
+	let shown: HashSet<*const EvNode> = txns.iter().flat_map(|tn| events(tn)).map(|e| Arc::as_ptr(&e)).collect();
+	let shown_txns: HashSet<*const TxnNode> = txns.iter().map(Arc::as_ptr).collect();
+	let mut out = String::from("digraph tpg {\n\trankdir=LR;\n\tcompound=true;\n\tnode [shape=box, fontsize=10];\n");
+
+	// (parent, son, state) -> (seen from son, seen from parent)
+	let mut read_edges: HashMap<(*const EvNode, *const EvNode, String), (bool, bool)> = HashMap::new();
+	let mut unfulfilled = HashSet::new();
+	for tn in &txns {
+		let _ = writeln!(out, "\tsubgraph cluster_{} {{", id(Arc::as_ptr(tn)));
+		let _ = writeln!(out, "\t\tlabel=\"txn {} type {} {:?} ts {}\";", tn.txn_req_id, tn.type_idx, tn.status.load(), tn.ts);
+		let _ = writeln!(out, "\t\t{} [label=\"txn {}\", shape=ellipse];", id(Arc::as_ptr(tn)), tn.txn_req_id);
+		for e in events(tn) {
+			let _ = writeln!(out, "\t\t{} [label=\"ev {} {:?}\\nR: {}\\nW: {}\"];",
+				id(Arc::as_ptr(&e)), e.idx, e.status.load(), e.reads.join(" "), e.writes.join(" "));
+			for (idx, p) in e.read_from.iter().enumerate() {
+				if let Some(parent) = p.try_read().and_then(|p| p.as_ref().and_then(|p| p.upgrade())) {
+					let key = (Arc::as_ptr(&parent), Arc::as_ptr(&e), e.reads[idx].clone());
+					read_edges.entry(key.clone()).or_default().0 = true;
+					if !e.is_fulfilled(idx) {
+						unfulfilled.insert(key);
+					}
+				}
+			}
+			if let Ok(read_by) = e.read_by.try_read() {
+				for son in read_by.iter().flatten().filter_map(|s| s.upgrade()) {
+					// Read_by does not tell the state. Matched to read_from entries of the son.
+					let states: Vec<String> = son.read_from.iter().enumerate()
+						.filter(|(_, p)| p.try_read().is_some_and(|p| p.as_ref().is_some_and(|p| p.as_ptr() == Arc::as_ptr(&e))))
+						.map(|(idx, _)| son.reads[idx].clone())
+						.collect();
+					if states.is_empty() {
+						read_edges.entry((Arc::as_ptr(&e), Arc::as_ptr(&son), String::from("?"))).or_default().1 = true;
+					}
+					states.into_iter().for_each(|s| read_edges.entry((Arc::as_ptr(&e), Arc::as_ptr(&son), s)).or_default().1 = true);
+				}
+			}
+		}
+		let _ = writeln!(out, "\t}}");
+	}
+	for ((parent, son, state), (from_son, from_parent)) in &read_edges {
+		if !shown.contains(parent) || !shown.contains(son) {
+			continue
+		}
+		let style = if !(from_son & from_parent) {
+			", color=orange"
+		} else if unfulfilled.contains(&(*parent, *son, state.clone())) {
+			", color=red, style=bold"
+		} else {
+			""
+		};
+		let _ = writeln!(out, "\t{} -> {} [label=\"{}\"{}];", id(*parent), id(*son), state, style);
+	}
+	// Cover edges, parent txn to son txn.
+	let mut cover_edges: HashMap<(*const TxnNode, *const TxnNode, String), (bool, bool)> = HashMap::new();
+	for tn in &txns {
+		if let Ok(cover) = tn.cover.try_read() {
+			cover.iter().filter_map(|(k, p)| Some((k, p.as_ref()?))).for_each(|(k, p)| {
+				cover_edges.entry((Arc::as_ptr(p), Arc::as_ptr(tn), k.clone())).or_default().0 = true;
+			});
+		}
+		if let Ok(covered_by) = tn.covered_by.try_read() {
+			covered_by.iter().filter_map(|(k, s)| Some((k, s.as_ref()?))).for_each(|(k, s)| {
+				cover_edges.entry((Arc::as_ptr(tn), Arc::as_ptr(s), k.clone())).or_default().1 = true;
+			});
+		}
+	}
+	for ((parent, son, state), (from_son, from_parent)) in &cover_edges {
+		if !shown_txns.contains(parent) || !shown_txns.contains(son) {
+			continue
+		}
+		let color = if from_son & from_parent { "" } else { ", color=orange" };
+		let _ = writeln!(out, "\t{} -> {} [label=\"{}\", style=dashed{}];", id(*parent), id(*son), state, color);
+	}
+	out += "}\n";
+	out
+}
+
+// Write to tpg-<ns>.dot under log_dir. Returns the path.
+pub fn dump(f: &DotFilter) -> std::io::Result<String> {
+	let log_dir = CONFIG.try_read().map(|c| c.log_dir.clone()).unwrap_or_else(|_| String::from("."));
+	let path = format!("{}/tpg-{}.dot", log_dir, utils::current_time_ns());
+	fs::write(&path, render(f))?;
+	Ok(path)
+}
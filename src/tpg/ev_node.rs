@@ -1,13 +1,17 @@
-use std::sync::{mpsc::*, Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use crossbeam::atomic::AtomicCell;
 
 use crate::database::api::Database;
 use crate::database::simpledb::{self};
-use crate::ds::events::Event;
+use crate::ds::events::{Event, MergeOp};
 use crate::external::ffi;
 use crate::tpg::txn_node::TxnStatus;
-use crate::utils::ShouldSyncCell;
+use crate::monitor::latency::{self, Stage};
+use crate::utils::{self, ShouldSyncCell};
 
+use super::ready_queue::EventSink;
+use super::scheduling::{self, SchedulingPolicy};
+use super::tpg::TPG;
 use super::txn_node::TxnNode;
 
 #[derive(Debug)]
@@ -30,23 +34,58 @@ pub struct EvNode{
 	 */
 	pub read_by: RwLock<Vec<Option<Weak<EvNode>>>>, // Could be updated during running.
 
+	/*
+		merge_from: Merge events on read states that are not reached by read_from.
+		- Value of a merged state is resolved from all deltas since the last plain write, so all of them should be accepted.
+		- Set by construct thread before linking to parents. Never changed after.
+		- The bool marks fulfilled, as is_read_from_fulfilled.
+	 */
+	pub merge_from: ShouldSyncCell<Vec<(Weak<EvNode>, AtomicCell<bool>)>>,
+
 	// Meta
 	pub txn: Weak<TxnNode>,
 	pub status: AtomicCell<EventStatus>,
+	/*
+		epoch: Generation of inputs. Increased before the node is reset by abortion of its parents.
+		- Worker records it when claiming, and discards the result if it changed during execution.
+		- Only a reset CLAIMED node is set back to WAITING, so the node is rescheduled once it's ready again.
+	 */
+	pub epoch: AtomicCell<u32>,
+	// When it got ready last time, claimed or queued. 0 before. For latency histograms.
+	pub ready_at: AtomicCell<u64>,
 	
 	// A vector is used to solve the multi-thread visiting.
 	is_read_from_fulfilled: Vec<AtomicCell<bool>>,
+	// Read_from entries on pessimistic states. Fulfilled when the parent txn commits, instead of when the parent accepted.
+	pub(in crate::tpg) held: Vec<AtomicCell<bool>>,
+	// Values of read_from entries sent by parents on other partitions. Taken instead of reading their versions in partitioned mode.
+	forwarded: Vec<Mutex<Option<Vec<u8>>>>,
 
 	// States to read.
 	pub reads: Vec<String>,
-	pub write: String,
-	pub has_write: bool,
+	// States to write. Empty if the event has no write. The first one is the template write.
+	pub writes: Vec<String>,
+	pub merge: Option<MergeOp>,
 
 	// Double sync for state writing.
 	has_storage_slot: AtomicCell<bool>,
 
 	// Router to execute function.
 	pub idx: i32,
+
+	// Dynamic key. Index of the event in the same txn giving the row to read and write.
+	pub key_from: Option<usize>,
+	// Row index parsed from the last result. Used by later dynamic events.
+	pub key_result: AtomicCell<Option<usize>>,
+}
+
+// How far an abortion cascades. Depth in read_by levels, fanout in accepted events reset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cascade {
+	pub depth: usize,
+	pub fanout: usize,
+	// Aborted events whose states turned pessimistic for this cascade.
+	pub pessimistic: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -61,11 +100,22 @@ pub enum EventStatus{
 	// No Garbage. It would be collected along with the whole transaction.
 }
 
+// EvNodes alive, including those of transactions committed but not yet released. Exported by the monitor.
+pub static LIVE_EV_NODES: AtomicCell<i64> = AtomicCell::new(0);
+
+impl Drop for EvNode {
+	fn drop(&mut self) {
+		LIVE_EV_NODES.fetch_sub(1);
+	}
+}
+
 impl EvNode {
 	// Only used to create node from template.
-	pub(in crate::tpg) fn from_template(event: &Event, idx: i32, txn: Weak<TxnNode>, reads_idx: Vec<usize>, write_idx: usize) -> Option<Self> {
+	// inputs are write keys of earlier events in the same transaction. They are read after reads.
+	// writes are keys composed from all_writes of the template and their rows.
+	pub(in crate::tpg) fn from_template(event: &Event, idx: i32, txn: Weak<TxnNode>, reads_idx: Vec<usize>, writes: Vec<String>, inputs: Vec<String>) -> Option<Self> {
 		// Template
-		let reads_length = event.reads.len();
+		let reads_length = event.reads.len() + inputs.len();
         
         // Pre-allocate read_from and is_read_from_fulfilled vectors
         let mut read_from = Vec::with_capacity(reads_length);
@@ -73,30 +123,95 @@ impl EvNode {
 		    read_from.push(ShouldSyncCell::new(None));
 		}
         let is_read_from_fulfilled = std::iter::repeat_with(|| AtomicCell::new(false))
+            .take(reads_length)
+            .collect();
+        let held = std::iter::repeat_with(|| AtomicCell::new(false))
+            .take(reads_length)
+            .collect();
+        let forwarded = std::iter::repeat_with(|| Mutex::new(None))
             .take(reads_length)
             .collect();
 
-		if reads_idx.len() < event.reads.len() {
+		if reads_idx.len() < event.reads.len() || writes.len() != event.all_writes().len() {
 			None
 		} else {
+			LIVE_EV_NODES.fetch_add(1);
 			Some(EvNode {
 				read_from,
 				read_by: RwLock::new(Vec::new()),
+				merge_from: ShouldSyncCell::new(Vec::new()),
 				txn,
 				status: AtomicCell::new(EventStatus::CONSTRUCT),
+				epoch: AtomicCell::new(0),
+				ready_at: AtomicCell::new(0),
 				is_read_from_fulfilled,
+				held,
+				forwarded,
 				reads: event.reads
 					.iter().enumerate()
-					.map(|(idx, k)| format!("{}_{}", k, reads_idx[idx])).collect(),
-				write: format!("{}_{}", event.write.clone(), write_idx),
+					.map(|(idx, k)| format!("{}_{}", k, reads_idx[idx]))
+					.chain(inputs).collect(),
+				writes,
 
 				idx,
-				has_write: event.has_write,
+				merge: event.merge,
 				has_storage_slot: AtomicCell::new(false),
+				key_from: event.key_from,
+				key_result: AtomicCell::new(None),
 			})
 		}
 	}
 
+	// Placeholder of dynamic event, whose key is unknown. Never linked nor executed.
+	// reads holds keys of inputs, which do not depend on the dynamic key.
+	pub(in crate::tpg) fn unresolved(event: &Event, idx: i32, txn: Weak<TxnNode>, inputs: Vec<String>) -> Self {
+		debug_assert!(event.key_from.is_some());
+		LIVE_EV_NODES.fetch_add(1);
+		EvNode {
+			read_from: Vec::new(),
+			read_by: RwLock::new(Vec::new()),
+			merge_from: ShouldSyncCell::new(Vec::new()),
+			txn,
+			status: AtomicCell::new(EventStatus::CONSTRUCT),
+			epoch: AtomicCell::new(0),
+			ready_at: AtomicCell::new(0),
+			is_read_from_fulfilled: Vec::new(),
+			held: Vec::new(),
+			forwarded: Vec::new(),
+			reads: inputs,
+			writes: Vec::new(),
+			idx,
+			merge: event.merge,
+			has_storage_slot: AtomicCell::new(false),
+			key_from: event.key_from,
+			key_result: AtomicCell::new(None),
+		}
+	}
+
+	// Node of no txn, reading and writing row 0 of the states. At most one write.
+	#[cfg(test)]
+	pub(in crate::tpg) fn for_test(idx: i32, reads: &[&str], writes: &[&str]) -> Arc<Self> {
+		let event: Event = serde_json::from_value(serde_json::json!({
+			"reads": reads, "write": writes.first().unwrap_or(&""), "has_write": !writes.is_empty(),
+		})).unwrap();
+		let writes = writes.iter().map(|w| format!("{}_0", w)).collect();
+		Arc::new(EvNode::from_template(&event, idx, Weak::new(), vec![0; reads.len()], writes, Vec::new()).unwrap())
+	}
+
+	// Got ready. Call after claiming or queueing it from WAITING. Link to ready latency is counted the first time.
+	pub fn mark_ready(&self) {
+		let now = utils::current_time_ns();
+		if self.ready_at.swap(now) != 0 {
+			return
+		}
+		if let Some(tn) = self.txn.upgrade() {
+			let linked_at = tn.linked_at.load();
+			if linked_at != 0 {
+				latency::record(tn.type_idx, Stage::LinkToReady, now.saturating_sub(linked_at));
+			}
+		}
+	}
+
 	pub fn ready(&self) -> bool{
 		// Thread safe. Reusable.
 		match self.status.load() {
@@ -106,13 +221,24 @@ impl EvNode {
 		self.no_waiting()
 	}
 
+	// Whether read idx got its parent result.
+	pub fn is_fulfilled(&self, idx: usize) -> bool {
+		self.is_read_from_fulfilled[idx].load()
+	}
+
+	// Whether read idx waits for the parent txn to commit.
+	pub fn is_held(&self, idx: usize) -> bool {
+		self.held[idx].load()
+	}
+
 	// Nobody is waiting. May used before enqueue, since state is CONSTRUCT.
 	pub fn no_waiting(&self) -> bool {
-		self.is_read_from_fulfilled.iter().all(|i| i.load() == true)
+		self.is_read_from_fulfilled.iter().all(|i| i.load())
+			&& self.merge_from.read().iter().all(|(_, i)| i.load())
 	}
 
-	// Wrapper. Calling execution handler.
-	pub fn execute(&self, values: &Vec<Vec<u8>>, cnt: i32) -> (bool, Vec<u8>) {
+	// Wrapper. Calling execution handler. Returns abort reason if aborted, otherwise one result for each state written.
+	pub fn execute(&self, values: &[Vec<u8>], cnt: i32) -> (Option<ffi::AbortReason>, Vec<Vec<u8>>) {
 		let mut value = Vec::new();
 
 		for (i, vec) in values.iter().enumerate() {
@@ -122,12 +248,26 @@ impl EvNode {
 			value.extend_from_slice(vec); // Append the bytes from the current vector
 		}
 		let txn_req_id = self.txn.upgrade().unwrap().txn_req_id;
-		ffi::execute_event(
+		let (abortion, res) = ffi::execute_event(
 			txn_req_id,
 			self.idx, 
 			value, 
 			cnt
-		) // For now, let param_count as the same as query.
+		); // For now, let param_count as the same as query.
+		if abortion.is_some() {
+			return (abortion, Vec::new())
+		}
+		let results = if self.writes.len() <= 1 {
+			vec![res]
+		} else {
+			match ffi::split_results(&res, self.writes.len()) {
+				Some(results) => results,
+				None => return (Some(ffi::AbortReason::new(ffi::ABORT_MALFORMED_RESULT, "malformed result")), Vec::new()),
+			}
+		};
+		// Key for later dynamic events. Stored before accepted.
+		self.key_result.store(Some(ffi::row_from_result(&results[0])));
+		(None, results)
 	}
 
 	pub fn notify_txn_accept(&self) {
@@ -151,49 +291,146 @@ impl EvNode {
 
 	// Operate on dependency. Init. This should be used when iterating the read_from link.
 	pub fn set_fulfilled_by_idx(&self, idx: usize, target: bool) -> bool {
-		self.is_read_from_fulfilled[idx].swap(true)
+		self.is_read_from_fulfilled[idx].swap(target)
 	}
 
-	// Operate on dependency.
-	pub fn set_unfulfilled_by_key(&self, key: &str) -> bool {
-		let idx = self.reads.iter().position(|r| r.as_str() == key).unwrap();
-		self.is_read_from_fulfilled[idx].swap(false)
+	// Init dependency once linked. Parents accepted (or committed, for held entries) are fulfilled.
+	pub fn init_fulfilled(&self) {
+		self.read_from.iter().enumerate().for_each(|(idx, last)|{
+			let fulfilled = last.read().as_ref().is_none_or(|last| {
+				let last = last.upgrade().unwrap();
+				if self.held[idx].load() {
+					last.txn.upgrade().unwrap().status.load() == TxnStatus::COMMITED
+				} else {
+					last.status.load() == EventStatus::ACCEPTED
+				}
+			});
+			self.set_fulfilled_by_idx(idx, fulfilled);
+		});
+		self.merge_from.read().iter().for_each(|(parent, fulfilled)| {
+			fulfilled.store(parent.upgrade().unwrap().status.load() == EventStatus::ACCEPTED);
+		});
+	}
+
+	// Operate on dependency. Parent is found by pointer, since merge events could be reached by key more than once.
+	pub fn set_unfulfilled_by_parent(&self, parent: &EvNode) -> bool {
+		let mut origin = false;
+		self.read_from.iter().enumerate().for_each(|(idx, p)| {
+			if p.read().as_ref().is_some_and(|p| std::ptr::eq(p.as_ptr(), parent)) {
+				origin |= self.is_read_from_fulfilled[idx].swap(false);
+				*self.forwarded[idx].lock().unwrap() = None; // Parent would write again.
+			}
+		});
+		self.merge_from.read().iter().for_each(|(p, fulfilled)| {
+			if std::ptr::eq(p.as_ptr(), parent) {
+				origin |= fulfilled.swap(false);
+			}
+		});
+		origin
 	}
 
 	// reset resets the related state modification to original. 
 	// Only apply to those event node that aborted or whose parents aborted after being accept.
-	pub fn reset_accept(&self){
-		debug_assert!(self.status.load() == EventStatus::ACCEPTED);
-		self.status.store(EventStatus::WAITING);
+	// Returns false if it's not ACCEPTED anymore, namely reset by others.
+	pub fn reset_accept(&self) -> bool {
+		self.epoch.fetch_add(1);
+		if self.status.compare_exchange(EventStatus::ACCEPTED, EventStatus::WAITING).is_err() {
+			return false
+		}
 		// Revert txn count.
 		self.txn.upgrade().unwrap().reset_fulfilled_event();
-		simpledb::DB.get().unwrap()
-			.reset_version("default", &self.write, self.txn.upgrade().unwrap().ts);
+		let ts = self.txn.upgrade().unwrap().ts;
+		self.writes.iter().for_each(|write| {
+			simpledb::DB.get().unwrap()
+				.reset_version("default", write, ts);
+		});
+		true
 	}
 
-	pub fn write_back<T: Database>(&self, value: &Vec<u8>, db: &T) {
-		if self.has_storage_slot.swap(true) {
-			db.write_version(
-				"default", 
-				self.write.as_str(), 
-				self.txn.upgrade().unwrap().ts, 
-				value,
-			);
-		} else {
-			db.push_version(
-				"default", 
-				self.write.as_str(), 
-				self.txn.upgrade().unwrap().ts, 
-				value,
-			);
+	// Reserve the version slot in ts order. Called by construct thread when linking.
+	pub(in crate::tpg) fn reserve_slot(&self) {
+		debug_assert!(self.status.load() == EventStatus::CONSTRUCT);
+		let ts = self.txn.upgrade().unwrap().ts;
+		self.writes.iter().for_each(|write| {
+			simpledb::DB.get().unwrap()
+				.reserve_version("default", write, ts);
+		});
+		self.has_storage_slot.store(true);
+	}
+
+	// One value for each state written, at ts of the txn. Deltas of every state written for merge events, see Event.merge.
+	pub fn write_back<T: Database>(&self, values: &[Vec<u8>], ts: u64, db: &T) {
+		debug_assert!(values.len() >= self.writes.len());
+		let has_slot = self.has_storage_slot.swap(true);
+		self.writes.iter().zip(values.iter()).for_each(|(write, value)| {
+			if let Some(op) = self.merge {
+				db.write_delta(
+					"default", 
+					write.as_str(), 
+					ts, 
+					value,
+					op,
+				);
+			} else if has_slot {
+				db.write_version(
+					"default", 
+					write.as_str(), 
+					ts, 
+					value,
+				);
+			} else {
+				db.push_version(
+					"default", 
+					write.as_str(), 
+					ts, 
+					value,
+				);
+			}
+		});
+	}
+
+	/*
+		Send written values to sons on other partitions, so they do not read the versions on this partition.
+		Call after write_back, before sons are informed. Not for merge events, whose values are deltas,
+		nor for sons waiting for merges, which read the values resolved.
+		Versions are still written, as later transactions, aborts and checkpoints go by them.
+		Returns the count sent.
+	 */
+	pub fn forward(&self, values: &[Vec<u8>], partition: usize, policy: &dyn SchedulingPolicy) -> usize {
+		if self.merge.is_some() {
+			return 0
 		}
+		let mut cnt = 0;
+		for son in self.read_by.read().unwrap().iter() {
+			let node = son.as_ref().unwrap().upgrade().unwrap();
+			if policy.home(partition, &node) == partition || !node.merge_from.read().is_empty() {
+				continue
+			}
+			node.read_from.iter().enumerate().for_each(|(idx, p)| {
+				if p.read().as_ref().is_some_and(|p| std::ptr::eq(p.as_ptr(), self)) {
+					if let Some(w) = self.writes.iter().position(|w| *w == node.reads[idx]) {
+						*node.forwarded[idx].lock().unwrap() = Some(values[w].clone());
+						cnt += 1;
+					}
+				}
+			});
+		}
+		cnt
+	}
+
+	// Value forwarded by the parent of read idx, if any. Taken once. Read the version if executed again.
+	pub fn take_forwarded(&self, idx: usize) -> Option<Vec<u8>> {
+		self.forwarded[idx].lock().unwrap().take()
 	}
 
 	// Add a new evNode reading this node's result.
 	pub fn add_read_by(&self, son: &Arc<EvNode>){
 		// Add to self read by.
 		self.read_by.write().unwrap().push(Some(Arc::downgrade(son)));
-		// Add to txn read by.
+		// Add to txn read by. Not for events in the same transaction.
+		if self.txn.ptr_eq(&son.txn) {
+			return
+		}
 		if !self.txn.upgrade().unwrap().read_by
 			.read().unwrap()
 			.iter().any(
@@ -207,43 +444,47 @@ impl EvNode {
 		}
 	}
 
-	pub fn get_next_option_push_others_ready(&self, pipe: &Sender<Arc<EvNode>>) -> Option<Arc<EvNode>> {
+	pub fn get_next_option_push_others_ready(&self, pipe: &dyn EventSink) -> Option<Arc<EvNode>> {
 		debug_assert!(self.status.load() == EventStatus::ACCEPTED
 			|| self.status.load() == EventStatus::ABORTED	 // Being aborted after writing back. Just select and enqueue the next ones.
 		);
 
 		// Traverse sons to inform acceptance, and return the next node.
 		let next_candidates: Vec<Arc<EvNode>> = (*self.read_by.read().unwrap())
-			.iter().enumerate().filter(|(idx, node)| 
+			.iter().filter(|node| 
 			{
 				// Test who is ready. Will ignore those are under construction.
 				node.as_ref().is_some_and(|n| {
-					n.upgrade().unwrap().parent_accepted(*idx); // Inform parent ready.
+					n.upgrade().unwrap().parent_accepted(self); // Inform parent ready.
 					n.upgrade().unwrap().ready()
 				})
 			})
-			.map(|(_, node)| 
+			.map(|node| 
 				{
 					node.as_ref().unwrap().upgrade().unwrap()
 				}
 			).collect();
-		// Compare TS and select the minimum as the next.
-		let min_evnode = next_candidates
-			.iter()
-			.min_by_key(|&evnode| 
-				evnode.txn.upgrade().unwrap().ts
-			);
-		// Assign the next. Push others to queue.
-		if min_evnode.is_some(){
-			for evnode in &next_candidates {
-				if !std::ptr::eq(evnode, *min_evnode.as_ref().unwrap()) {
-					pipe.send(evnode.clone()).unwrap();
-				}
+		// Select the next by the scheduling policy.
+		let min_evnode = TPG.get().unwrap().policy
+			.pick_next(pipe.worker(), &next_candidates)
+			.map(|i| &next_candidates[i]);
+		// Assign the next. Push others to queue, the most urgent first.
+		let mut others: Vec<&Arc<EvNode>> = next_candidates.iter().collect();
+		others.sort_by_key(|ev| scheduling::urgency(ev));
+		for evnode in others {
+			// Those already in queue, or claimed by others, are not sent again.
+			if !min_evnode.is_some_and(|min| std::ptr::eq(evnode, min)) 
+				&& evnode.status.compare_exchange(EventStatus::WAITING, EventStatus::INQUEUE).is_ok() {
+				evnode.mark_ready();
+				pipe.push(evnode.clone());
 			}
+		}
+		if let Some(min) = min_evnode {
 			// This evnode has been selected as min. Try lock with CAS to claim that node.
-			match min_evnode.unwrap().status.compare_exchange(EventStatus::WAITING, EventStatus::CLAIMED) {
+			match min.status.compare_exchange(EventStatus::WAITING, EventStatus::CLAIMED) {
 				Ok(_) =>  {
-					Some(min_evnode.unwrap().clone())
+					min.mark_ready();
+					Some(min.clone())
 				},
 				// Has been claimed by Construct threads.
 				Err(state) => {
@@ -257,12 +498,32 @@ impl EvNode {
 		}
 	}
 
+	// Called when the txn of this event committed. Sons held on this event are fulfilled and enqueued if ready.
+	pub fn release_held(&self, pipe: &dyn EventSink) {
+		for son in self.read_by.read().unwrap().iter() {
+			let node = son.as_ref().unwrap().upgrade().unwrap();
+			let mut released = false;
+			node.read_from.iter().enumerate().for_each(|(idx, p)| {
+				if node.held[idx].load() && p.read().as_ref().is_some_and(|p| std::ptr::eq(p.as_ptr(), self)) {
+					node.is_read_from_fulfilled[idx].swap(true);
+					released = true;
+				}
+			});
+			// Nodes under construction are enqueued by construct thread.
+			if released && node.ready() && node.status.compare_exchange(EventStatus::WAITING, EventStatus::INQUEUE).is_ok() {
+				node.mark_ready();
+				pipe.push(node);
+			}
+		}
+	}
+
 	/*
 		Abort the operation and then notify the whole transaction.
 		This function called when:
 		- CLAIMED evnode and WAITING transaction.
 	 */
-	pub fn notify_txn_abort(&self){
+	// Returns the cascade if this abortion takes effect on the txn.
+	pub fn notify_txn_abort(&self, reason: ffi::AbortReason) -> Option<Cascade> {
 		debug_assert!(
 			(self.status.load() == EventStatus::CLAIMED 
 		 	|| self.status.load() == EventStatus::ABORTED) // Abort during execution.
@@ -271,7 +532,7 @@ impl EvNode {
 			self.txn.upgrade().unwrap().status.load() != TxnStatus::COMMITED
 		);
 		// Trigger txn abortion.
-		self.txn.upgrade().unwrap().abort();
+		self.txn.upgrade().unwrap().abort(reason)
 		// self.abort(); // Txn abortion includes self abortion.
 	}
 
@@ -295,76 +556,185 @@ impl EvNode {
 		- If an operation has been marked ACCEPTED, we believe its state change has happened. So we do recover works.
 		- If an operation has been marked ABORTED, we believe its state has been properly handled. We do nothing about it.
 	 */
-	pub	fn abort(&self) {
+	// Returns how far the abortion cascades.
+	pub	fn abort(&self) -> Cascade {
 		debug_assert!( self.status.load() != EventStatus::CONSTRUCT ); // A node can be aborted anytime but not construct. The whole txn is not ready to work.
 		debug_assert!( self.txn.upgrade().unwrap().status.load() == TxnStatus::ABORTED );
+		let mut cascade = Cascade::default();
 		// Has been aborted by others.
 		if self.status.load() == EventStatus::ABORTED {
-			return
+			return cascade
 		}
 		self.status.store(EventStatus::ABORTED);
 		// TODO. Preallocate the vector.
-		let mut stack: Vec<Arc<EvNode>> = Vec::with_capacity(20);
+		let mut stack: Vec<(Arc<EvNode>, usize)> = Vec::with_capacity(20);
 		// Shit... I just want a Arc<EvNode> of self...
-		stack.push(self.txn.upgrade().unwrap().ev_nodes.read()[self.idx as usize].clone());
+		stack.push((self.txn.upgrade().unwrap().ev_nodes.read().unwrap()[self.idx as usize].clone(), 0));
 		while stack.len() != 0 {
-			let parent = stack.pop().unwrap();
+			let (parent, depth) = stack.pop().unwrap();
 			for son in parent.read_by.read().unwrap().iter(){
 				let node = son.as_ref().unwrap().upgrade().unwrap(); // Son could not be none.
-				match node.status.load() {
-					EventStatus::ACCEPTED => {
-						node.reset_accept();
-						let origin_fullfilled = node.set_unfulfilled_by_key(&parent.write);
-						debug_assert!(origin_fullfilled); // Orginally must be true. Set false now.
-						// State shift has been made. Recover the state shift and dive in.
-						// Reset later dependent nodes.
-						stack.push(node); // It could produce wrong result to be used by sons.
-					}
-					EventStatus::ABORTED => {}, // Ends here. Has been operated by other abortion thread.
-					EventStatus::CLAIMED => {
-						node.status.store(EventStatus::WAITING);
-						println!("Event Abortion: Rare condition. A claimed node needs to be reset.");
-						// Busy wait till that thread release Event.
-						// while node.status.load() != EventStatus::CLAIMED {}
-						let origin_fullfilled = node.set_unfulfilled_by_key(&parent.write);
-						debug_assert!(origin_fullfilled == false); // Orginally must be false.
-					},
-					EventStatus::WAITING => {
-						// No state shift happened. No change.
-						let origin_fullfilled = node.set_unfulfilled_by_key(&parent.write);
-						debug_assert!(origin_fullfilled == false); // Not executed yet. ?? Confused...
-					},
-					EventStatus::INQUEUE => {
-						node.status.store(EventStatus::WAITING); // Just set waiting. Invalidate the following.
-						let origin_fullfilled = node.set_unfulfilled_by_key(&parent.write);
-						debug_assert!(origin_fullfilled == false);
-						// Nothing to push. It's not done yet.
+				if node.txn.ptr_eq(&self.txn) {
+					continue // Reading inside the aborted transaction. Aborted along with it.
+				}
+				// Loop till the reset applies on a stable status. Workers could accept it meanwhile.
+				loop {
+					match node.status.load() {
+						EventStatus::ACCEPTED => {
+							if !node.reset_accept() {
+								continue // Reset or aborted by others meanwhile. Check again.
+							}
+							let origin_fullfilled = node.set_unfulfilled_by_parent(&parent);
+							debug_assert!(origin_fullfilled); // Orginally must be true. Set false now.
+							// State shift has been made. Recover the state shift and dive in.
+							// Reset later dependent nodes.
+							cascade.fanout += 1;
+							cascade.depth = cascade.depth.max(depth + 1);
+							stack.push((node, depth + 1)); // It could produce wrong result to be used by sons.
+						}
+						EventStatus::ABORTED => {}, // Ends here. Has been operated by other abortion thread.
+						EventStatus::CLAIMED => {
+							// The worker discards its result by epoch. It's rescheduled once its parents accepted again.
+							node.epoch.fetch_add(1);
+							if node.status.compare_exchange(EventStatus::CLAIMED, EventStatus::WAITING).is_err() {
+								continue // Accepted or aborted meanwhile. Check again.
+							}
+							node.set_unfulfilled_by_parent(&parent);
+						},
+						EventStatus::WAITING => {
+							// No state shift happened. No change.
+							let origin_fullfilled = node.set_unfulfilled_by_parent(&parent);
+							debug_assert!(!origin_fullfilled); // Not executed yet. ?? Confused...
+						},
+						EventStatus::INQUEUE => {
+							// Just set waiting. Invalidate the following. Worker drops it when popped.
+							node.epoch.fetch_add(1);
+							if node.status.compare_exchange(EventStatus::INQUEUE, EventStatus::WAITING).is_err() {
+								continue // Claimed meanwhile. Check again.
+							}
+							let origin_fullfilled = node.set_unfulfilled_by_parent(&parent);
+							debug_assert!(!origin_fullfilled);
+							// Nothing to push. It's not done yet.
+						}
+						EventStatus::CONSTRUCT => panic!("bug."),
 					}
-					EventStatus::CONSTRUCT => panic!("bug."),
+					break
 				}
 			}
 		};
 		// Copy last state result only happens for aborted nodes. For those redo ones, just set empty with reset api.
-		if self.has_write{
-			simpledb::DB.get().unwrap()
-				.copy_last_version(
-					"default", 
-					&self.write, 
-					self.txn.upgrade().unwrap().ts, 
-					self.has_storage_slot.swap(true), // Get a new slot if not have.
-				); 
-		}
+		let ts = self.txn.upgrade().unwrap().ts;
+		let has_slot = self.has_storage_slot.swap(true); // Get a new slot if not have.
+		self.writes.iter().for_each(|write| {
+			if let Some(op) = self.merge {
+				// Aborted delta is identity.
+				simpledb::DB.get().unwrap()
+					.write_delta(
+						"default", 
+						write, 
+						ts, 
+						&Vec::new(),
+						op,
+					);
+			} else {
+				simpledb::DB.get().unwrap()
+					.copy_last_version(
+						"default", 
+						write, 
+						ts, 
+						has_slot,
+					); 
+			}
+		});
+		cascade
 	}
 
-	// Notification from parents in read_from.
-	fn parent_accepted(&self, idx: usize) {
+	// Notification from parents in read_from or merge_from.
+	fn parent_accepted(&self, parent: &EvNode) {
 		debug_assert!(
 			match self.status.load(){
 				EventStatus::CONSTRUCT | EventStatus::WAITING | EventStatus::INQUEUE => true,
 				_ => { println!("{:?}", self.status.load()); false }
 			}
 		);
-	    self.is_read_from_fulfilled[idx].swap(true);
+		self.read_from.iter().enumerate().for_each(|(idx, p)| {
+			// Held entries wait for the parent txn to commit.
+			if !self.held[idx].load() && p.read().as_ref().is_some_and(|p| std::ptr::eq(p.as_ptr(), parent)) {
+				self.is_read_from_fulfilled[idx].swap(true);
+			}
+		});
+		self.merge_from.read().iter().for_each(|(p, fulfilled)| {
+			if std::ptr::eq(p.as_ptr(), parent) {
+				fulfilled.swap(true);
+			}
+		});
 	}
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Each event is homed on the partition of its idx.
+	struct ByIdx;
+
+	impl SchedulingPolicy for ByIdx {
+		fn pick_next(&self, _: Option<usize>, _: &[Arc<EvNode>]) -> Option<usize> {
+			None
+		}
+		fn home(&self, _: usize, ev: &EvNode) -> usize {
+			ev.idx as usize
+		}
+	}
+
+	fn link(parent: &Arc<EvNode>, son: &Arc<EvNode>) {
+		*son.read_from[0].write() = Some(Arc::downgrade(parent));
+		parent.add_read_by(son);
+	}
+
+	#[test]
+	fn test_forward() {
+		let parent = EvNode::for_test(0, &[], &["b"]);
+		let local = EvNode::for_test(0, &["b"], &[]);
+		let remote = EvNode::for_test(1, &["b"], &["c"]);
+		link(&parent, &local);
+		link(&parent, &remote);
+
+		// Only to the son on another partition, and taken once.
+		assert_eq!(parent.forward(&[vec![7]], 0, &ByIdx), 1);
+		assert_eq!(local.take_forwarded(0), None);
+		assert_eq!(remote.take_forwarded(0), Some(vec![7]));
+		assert_eq!(remote.take_forwarded(0), None);
+
+		// Dropped once the parent is reset, as it would write again.
+		parent.forward(&[vec![8]], 0, &ByIdx);
+		remote.set_unfulfilled_by_parent(&parent);
+		assert_eq!(remote.take_forwarded(0), None);
+
+		// Not for sons waiting for merges.
+		remote.merge_from.write().push((Weak::new(), AtomicCell::new(false)));
+		assert_eq!(parent.forward(&[vec![9]], 0, &ByIdx), 0);
+	}
+
+	#[test]
+	fn test_merge_extra_writes() {
+		crate::database::simpledb::test::small_config();
+		let mut db = simpledb::SimpleDB::new();
+		db.add_table("default", vec!["a", "b"]);
+		let event: Event = serde_json::from_value(serde_json::json!({
+			"reads": [], "write": "a", "has_write": true, "extra_writes": ["b"], "merge": "add",
+		})).unwrap();
+		let writes = vec![String::from("a_0"), String::from("b_0")];
+		let ev = EvNode::from_template(&event, 0, Weak::new(), Vec::new(), writes, Vec::new()).unwrap();
+		db.push_version("default", "a_0", 1, &10i64.to_le_bytes().to_vec());
+		db.push_version("default", "b_0", 1, &20i64.to_le_bytes().to_vec());
+		db.reserve_version("default", "a_0", 2);
+		db.reserve_version("default", "b_0", 2);
+
+		// Extra writes are deltas too.
+		ev.write_back(&[1i64.to_le_bytes().to_vec(), 2i64.to_le_bytes().to_vec()], 2, &db);
+		assert_eq!(db.get_version("default", "a_0", 2), 11i64.to_le_bytes().to_vec());
+		assert_eq!(db.get_version("default", "b_0", 2), 22i64.to_le_bytes().to_vec());
+	}
+}
@@ -1,3 +1,6 @@
 pub mod tpg;
 pub mod ev_node;
-pub mod txn_node;
\ No newline at end of file
+pub mod txn_node;
+pub mod ready_queue;
+pub mod scheduling;
+pub mod dot;
\ No newline at end of file
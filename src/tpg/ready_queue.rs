@@ -0,0 +1,176 @@
+use std::sync::mpsc::*;
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::deque::Worker;
+
+use crate::config::CONFIG;
+
+use super::ev_node::EvNode;
+use super::tpg::TPG;
+use crate::worker::idle;
+
+/*
+	Ready queue with priority classes.
+	- One channel for each class. Events are routed by the priority of their txn. Higher is served first.
+	- To prevent starvation, after starvation_limit events served by priority in a row,
+		one event is served round robin from the classes, starting after the class served last time.
+ */
+pub struct ReadyQueue<T = Arc<EvNode>> {
+	senders: Vec<Sender<T>>,
+	// Events in each class. Shared with the receiver.
+	depths: Arc<Vec<AtomicCell<usize>>>,
+}
+
+pub struct ReadyReceiver<T = Arc<EvNode>> {
+	receivers: Vec<Receiver<T>>,
+	// Served by priority since the last round robin.
+	streak: usize,
+	starvation_limit: usize,
+	// Class to start from in the next round robin.
+	next_rr: usize,
+	depths: Arc<Vec<AtomicCell<usize>>>,
+}
+
+pub fn ready_queue() -> (ReadyQueue, ReadyReceiver) {
+	with_levels(CONFIG.read().unwrap().priority_levels as usize, CONFIG.read().unwrap().priority_starvation_limit)
+}
+
+pub(crate) fn with_levels<T>(levels: usize, starvation_limit: usize) -> (ReadyQueue<T>, ReadyReceiver<T>) {
+	let levels = levels.max(1);
+	let (senders, receivers) = (0..levels).map(|_| channel()).unzip();
+	let depths: Arc<Vec<_>> = Arc::new((0..levels).map(|_| AtomicCell::new(0)).collect());
+	(
+		ReadyQueue { senders, depths: depths.clone() },
+		ReadyReceiver {
+			receivers,
+			streak: 0,
+			starvation_limit,
+			next_rr: 0,
+			depths,
+		},
+	)
+}
+
+// Where ready events go. The global ready queue, or the local queue of a worker thread.
+pub trait EventSink {
+	fn push(&self, ev: Arc<EvNode>);
+	// Worker thread pushing. None outside worker threads.
+	fn worker(&self) -> Option<usize> {
+		None
+	}
+}
+
+impl EventSink for ReadyQueue {
+	fn push(&self, ev: Arc<EvNode>) {
+		self.send(ev).unwrap();
+	}
+}
+
+// Local queue of a worker thread. Events homed to other threads by the scheduling policy go to their inboxes.
+pub struct WorkerSink<'a> {
+	pub worker: usize,
+	pub local: &'a Worker<Arc<EvNode>>,
+}
+
+impl EventSink for WorkerSink<'_> {
+	fn push(&self, ev: Arc<EvNode>) {
+		let tpg = TPG.get().unwrap();
+		let home = tpg.policy.home(self.worker, &ev);
+		if home == self.worker {
+			self.local.push(ev);
+		} else {
+			tpg.inboxes[home].push(ev);
+		}
+		idle::wake_all(); // Idle threads could steal it.
+	}
+	fn worker(&self) -> Option<usize> {
+		Some(self.worker)
+	}
+}
+
+impl ReadyQueue {
+	pub fn send(&self, ev: Arc<EvNode>) -> Result<(), SendError<Arc<EvNode>>> {
+		// Partitioned mode. Straight to the owner.
+		if let Some(tpg) = TPG.get().filter(|tpg| tpg.partitioned) {
+			tpg.inboxes[tpg.policy.home(0, &ev)].push(ev);
+			idle::wake_all();
+			return Ok(())
+		}
+		let priority = ev.txn.upgrade().unwrap().priority as usize;
+		let ret = self.send_by_priority(priority, ev);
+		idle::wake_all();
+		ret
+	}
+}
+
+impl<T> ReadyQueue<T> {
+	// Priorities above the highest class go to the highest.
+	pub(crate) fn send_by_priority(&self, priority: usize, item: T) -> Result<(), SendError<T>> {
+		let class = priority.min(self.senders.len() - 1);
+		self.depths[class].fetch_add(1);
+		self.senders[class].send(item)
+	}
+
+	// Events queued in each priority class.
+	pub fn depths(&self) -> Vec<usize> {
+		self.depths.iter().map(|d| d.load()).collect()
+	}
+}
+
+impl<T> ReadyReceiver<T> {
+	pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+		let levels = self.receivers.len();
+		if self.starvation_limit != 0 && self.streak >= self.starvation_limit {
+			for i in 0..levels {
+				let class = (self.next_rr + i) % levels;
+				if let Ok(ev) = self.receivers[class].try_recv() {
+					self.depths[class].fetch_sub(1);
+					self.streak = 0;
+					self.next_rr = (class + 1) % levels;
+					return Ok(ev)
+				}
+			}
+			return Err(TryRecvError::Empty)
+		}
+		for class in (0..levels).rev() {
+			match self.receivers[class].try_recv() {
+				Ok(ev) => {
+					self.depths[class].fetch_sub(1);
+					self.streak += 1;
+					return Ok(ev)
+				},
+				Err(TryRecvError::Empty) => {},
+				Err(err) => return Err(err),
+			}
+		}
+		Err(TryRecvError::Empty)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Items in the order served till empty.
+	fn drain(rx: &mut ReadyReceiver<u32>) -> Vec<u32> {
+		std::iter::from_fn(|| rx.try_recv().ok()).collect()
+	}
+
+	#[test]
+	fn test_serve_order() {
+		// (priority, item). Priority 7 is beyond the highest class.
+		let sent = [(0, 1), (0, 2), (1, 3), (7, 4), (2, 5), (2, 6)];
+
+		let (tx, mut rx) = with_levels(3, 2);
+		sent.iter().for_each(|&(p, item)| tx.send_by_priority(p, item).unwrap());
+		assert_eq!(tx.depths(), vec![2, 1, 3]);
+		// Two by priority, then one round robin from the lowest class on, and so on.
+		assert_eq!(drain(&mut rx), vec![4, 5, 1, 6, 3, 2]);
+		assert_eq!(tx.depths(), vec![0, 0, 0]);
+
+		// By priority only.
+		let (tx, mut rx) = with_levels(3, 0);
+		sent.iter().for_each(|&(p, item)| tx.send_by_priority(p, item).unwrap());
+		assert_eq!(drain(&mut rx), vec![4, 5, 6, 3, 1, 2]);
+	}
+}
@@ -0,0 +1,140 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+
+use super::ev_node::EvNode;
+
+/*
+	Scheduling policy of executor threads. Decides:
+	- Which of the sons just made ready the finishing thread goes on with.
+	- Which worker thread the other ready sons are queued to.
+	Threads fetch from their local queue, then their inbox, then the global queue, and steal at last, whatever the policy.
+ */
+pub trait SchedulingPolicy: Send + Sync {
+	// Index of the candidate to go on with. Worker is None when called outside worker threads. None to queue them all.
+	fn pick_next(&self, worker: Option<usize>, candidates: &[Arc<EvNode>]) -> Option<usize>;
+	// Worker thread to queue a ready event not picked.
+	fn home(&self, worker: usize, _ev: &EvNode) -> usize {
+		worker
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyKind {
+	TimestampFirst,
+	DepthFirst,
+	KeyAffinity,
+	RoundRobin,
+}
+
+pub fn from_config() -> Box<dyn SchedulingPolicy> {
+	let workers = (CONFIG.read().unwrap().worker_threads_num as usize).max(1);
+	// Partitioned mode routes events by their keys.
+	if CONFIG.read().unwrap().partitioned {
+		return Box::new(KeyAffinity { workers })
+	}
+	match CONFIG.read().unwrap().scheduling_policy {
+		PolicyKind::TimestampFirst => Box::new(TimestampFirst),
+		PolicyKind::DepthFirst => Box::new(DepthFirst),
+		PolicyKind::KeyAffinity => Box::new(KeyAffinity { workers }),
+		PolicyKind::RoundRobin => Box::new(RoundRobin { workers, next: AtomicCell::new(0) }),
+	}
+}
+
+// Highest priority first, then the oldest.
+pub(crate) fn urgency(ev: &EvNode) -> (std::cmp::Reverse<u8>, u64) {
+	let tn = ev.txn.upgrade().unwrap();
+	(std::cmp::Reverse(tn.priority), tn.ts)
+}
+
+// Go on with the most urgent son. Others stay in the local queue.
+pub struct TimestampFirst;
+
+impl SchedulingPolicy for TimestampFirst {
+	fn pick_next(&self, _: Option<usize>, candidates: &[Arc<EvNode>]) -> Option<usize> {
+		(0..candidates.len()).min_by_key(|&i| urgency(&candidates[i]))
+	}
+}
+
+// Go on with the son read by the most events, to keep along the longest dependency chain.
+pub struct DepthFirst;
+
+impl SchedulingPolicy for DepthFirst {
+	fn pick_next(&self, _: Option<usize>, candidates: &[Arc<EvNode>]) -> Option<usize> {
+		(0..candidates.len()).min_by_key(|&i| {
+			(std::cmp::Reverse(candidates[i].read_by.read().unwrap().len()), urgency(&candidates[i]))
+		})
+	}
+}
+
+// Partition of a state key among workers.
+pub fn partition_of(key: &str, workers: usize) -> usize {
+	let mut hasher = DefaultHasher::new();
+	key.hash(&mut hasher);
+	hasher.finish() as usize % workers
+}
+
+// Events on the same state go to the same worker thread. Keyed by the first state written, or read if none.
+pub struct KeyAffinity {
+	workers: usize,
+}
+
+impl KeyAffinity {
+	fn key_home(&self, ev: &EvNode) -> usize {
+		ev.writes.first().or(ev.reads.first())
+			.map_or(0, |key| partition_of(key, self.workers))
+	}
+}
+
+impl SchedulingPolicy for KeyAffinity {
+	fn pick_next(&self, worker: Option<usize>, candidates: &[Arc<EvNode>]) -> Option<usize> {
+		let worker = worker?;
+		(0..candidates.len())
+			.filter(|&i| self.key_home(&candidates[i]) == worker)
+			.min_by_key(|&i| urgency(&candidates[i]))
+	}
+	fn home(&self, _: usize, ev: &EvNode) -> usize {
+		self.key_home(ev)
+	}
+}
+
+// Ready sons are spread over worker threads in turn.
+pub struct RoundRobin {
+	workers: usize,
+	next: AtomicCell<usize>,
+}
+
+impl SchedulingPolicy for RoundRobin {
+	fn pick_next(&self, _: Option<usize>, _: &[Arc<EvNode>]) -> Option<usize> {
+		None
+	}
+	fn home(&self, _: usize, _: &EvNode) -> usize {
+		self.next.fetch_add(1) % self.workers
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_key_affinity_home() {
+		let policy = KeyAffinity { workers: 4 };
+		let writer = EvNode::for_test(0, &["a"], &["b"]);
+		let reader = EvNode::for_test(1, &["b"], &[]);
+		// By the write key, or the read key if none.
+		assert_eq!(policy.home(3, &writer), partition_of("b_0", 4));
+		assert_eq!(policy.home(3, &reader), policy.home(0, &writer));
+		assert!(policy.home(0, &writer) < 4);
+		assert_eq!(policy.home(3, &EvNode::for_test(2, &[], &[])), 0);
+		// Never goes on with events of other workers.
+		let other = (policy.home(0, &writer) + 1) % 4;
+		assert_eq!(policy.pick_next(Some(other), &[writer.clone(), reader]), None);
+		assert_eq!(policy.pick_next(None, &[writer]), None);
+	}
+}
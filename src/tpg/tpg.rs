@@ -1,23 +1,42 @@
-use std::sync::{mpsc::*, Arc, Mutex, RwLock, Weak};
-use std::sync::mpsc::Receiver;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use once_cell::sync::OnceCell;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
 
 use crate::config::CONFIG;
 
 use super::{
 	txn_node::TxnNode,
 	ev_node::EvNode,
+	ready_queue::{self, ReadyQueue, ReadyReceiver},
+	scheduling::{self, SchedulingPolicy},
 };
 
 pub(crate) static TPG: OnceCell<Tpg> = OnceCell::new();
 
 // Tpg itself. applies to both Txn and Events.
 pub struct Tpg{
-	pub ready_queue_out: Mutex<Receiver<Arc<EvNode>>>,
-	pub ready_queue_in: Sender<Arc<EvNode>>,
+	pub ready_queue_out: Mutex<ReadyReceiver>,
+	pub ready_queue_in: ReadyQueue,
+	// Local queues of worker threads. Each takes its own, and the others steal from it by stealers.
+	// FIFO, so events are run in the order of priority they were pushed.
+	local_queues: Mutex<Vec<Option<Worker<Arc<EvNode>>>>>,
+	stealers: Vec<Stealer<Arc<EvNode>>>,
+	// Events queued to worker threads by others, as the scheduling policy decides.
+	pub inboxes: Vec<Injector<Arc<EvNode>>>,
+	pub policy: Box<dyn SchedulingPolicy>,
+	// Events only run on the worker thread owning their write key. See CONFIG.partitioned.
+	pub partitioned: bool,
 	pub state_last_modify: RwLock<HashMap<String, Option::<(Weak<EvNode>,Arc<TxnNode>)>>>,
+	// Uncommitted writers of each state since its last plain write, including that write. Readers wait for all of them.
+	pub state_pending_merges: RwLock<HashMap<String, Vec<Weak<EvNode>>>>,
+	// States whose readers wait for the writer to commit, since aborts on them cascaded too far. See CONFIG.cascade_threshold.
+	// Each with the clean commits left before it turns optimistic again.
+	pub pessimistic_states: RwLock<HashMap<String, AtomicCell<usize>>>,
+	// See CONFIG.pessimistic_commits.
+	pub pessimistic_commits: usize,
 }
 
 impl Tpg{
@@ -29,11 +48,154 @@ impl Tpg{
 			)
 		);
 
-		let (tx, rx) = channel();
+		let (tx, rx) = ready_queue::ready_queue();
+		let local_queues: Vec<_> = (0..CONFIG.read().unwrap().worker_threads_num)
+			.map(|_| Worker::new_fifo())
+			.collect();
+		let stealers = local_queues.iter().map(|w| w.stealer()).collect();
 		Tpg{
 			ready_queue_in: tx,
 			ready_queue_out: Mutex::new(rx),
+			local_queues: Mutex::new(local_queues.into_iter().map(Some).collect()),
+			inboxes: (0..CONFIG.read().unwrap().worker_threads_num).map(|_| Injector::new()).collect(),
+			stealers,
+			policy: scheduling::from_config(),
+			partitioned: CONFIG.read().unwrap().partitioned,
 			state_last_modify: RwLock::new(state_map),
+			state_pending_merges: RwLock::new(HashMap::new()),
+			pessimistic_states: RwLock::new(HashMap::new()),
+			pessimistic_commits: CONFIG.read().unwrap().pessimistic_commits,
 		}
 	}
+
+	// Readers of these states wait for the writer to commit from now on. Counted afresh if already pessimistic.
+	pub fn turn_pessimistic(&self, states: &[String]) {
+		let mut pessimistic_states = self.pessimistic_states.write().unwrap();
+		states.iter().for_each(|state| {
+			pessimistic_states.insert(state.clone(), AtomicCell::new(self.pessimistic_commits));
+		});
+	}
+
+	// A transaction writing these states committed. Those pessimistic turn optimistic after enough of them.
+	pub fn committed_clean(&self, states: &[String]) {
+		if self.pessimistic_commits == 0 {
+			return
+		}
+		let pessimistic_states = self.pessimistic_states.read().unwrap();
+		let done: Vec<&String> = states.iter()
+			.filter(|state| pessimistic_states.get(*state)
+				.is_some_and(|left| left.fetch_update(|n| n.checked_sub(1)) == Ok(1)))
+			.collect();
+		if done.is_empty() {
+			return
+		}
+		drop(pessimistic_states);
+		let mut pessimistic_states = self.pessimistic_states.write().unwrap();
+		done.into_iter().for_each(|state| {
+			// Turned pessimistic again meanwhile otherwise.
+			if pessimistic_states.get(state).is_some_and(|left| left.load() == 0) {
+				pessimistic_states.remove(state);
+			}
+		});
+	}
+
+	// Taken once by each worker thread.
+	pub fn take_local_queue(&self, worker_idx: usize) -> Worker<Arc<EvNode>> {
+		self.local_queues.lock().unwrap()[worker_idx].take().expect("local queue taken twice.")
+	}
+
+	/*
+		Live transactions, in ts order. Reached from the last modifiers of states, through links of both directions.
+		Locks held by others are skipped rather than waited for, so it's safe to call from a panic hook,
+		but may miss parts then.
+	 */
+	pub fn live_txns(&self) -> Vec<Arc<TxnNode>> {
+		let mut queue: VecDeque<Arc<TxnNode>> = VecDeque::new();
+		if let Ok(last_modify) = self.state_last_modify.try_read() {
+			queue.extend(last_modify.values().flatten().map(|(_, tn)| tn.clone()));
+		}
+		let mut seen = HashSet::new();
+		let mut txns = Vec::new();
+		while let Some(tn) = queue.pop_front() {
+			if !seen.insert(Arc::as_ptr(&tn)) {
+				continue
+			}
+			if let Ok(l) = tn.read_from.try_read() { queue.extend(l.iter().flatten().cloned()) }
+			if let Ok(l) = tn.read_by.try_read() { queue.extend(l.iter().flatten().cloned()) }
+			if let Ok(l) = tn.cover.try_read() { queue.extend(l.values().flatten().cloned()) }
+			if let Ok(l) = tn.covered_by.try_read() { queue.extend(l.values().flatten().cloned()) }
+			txns.push(tn);
+		}
+		txns.sort_by_key(|tn| (tn.ts, tn.txn_req_id));
+		txns
+	}
+
+	// Events in the local queue and the inbox of each worker thread.
+	pub fn worker_depths(&self) -> Vec<(usize, usize)> {
+		self.stealers.iter().zip(self.inboxes.iter())
+			.map(|(s, i)| (s.len(), i.len()))
+			.collect()
+	}
+
+	// Events queued to the worker thread by others.
+	pub fn pop_inbox(&self, worker_idx: usize) -> Option<Arc<EvNode>> {
+		loop {
+			match self.inboxes[worker_idx].steal() {
+				Steal::Success(ev) => return Some(ev),
+				Steal::Empty => return None,
+				Steal::Retry => continue,
+			}
+		}
+	}
+
+	// Steal the oldest event from local queues of other worker threads. Never in partitioned mode, where events stay on their owners.
+	pub fn steal(&self, thief: usize) -> Option<Arc<EvNode>> {
+		if self.partitioned {
+			return None
+		}
+		let n = self.stealers.len();
+		for i in 1..n {
+			loop {
+				match self.stealers[(thief + i) % n].steal() {
+					Steal::Success(ev) => return Some(ev),
+					Steal::Empty => break,
+					Steal::Retry => continue,
+				}
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_no_steal_when_partitioned() {
+		let mut tpg = Tpg::new(Vec::new());
+		let local = tpg.take_local_queue(0);
+		local.push(EvNode::for_test(0, &[], &["a"]));
+		tpg.partitioned = true;
+		assert!(tpg.steal(1).is_none());
+		tpg.partitioned = false;
+		assert!(tpg.steal(1).is_some());
+	}
+
+	#[test]
+	fn test_pessimistic_decay() {
+		let mut tpg = Tpg::new(Vec::new());
+		tpg.pessimistic_commits = 2;
+		let states = vec![String::from("a_0")];
+		tpg.turn_pessimistic(&states);
+		tpg.committed_clean(&states);
+		assert!(tpg.pessimistic_states.read().unwrap().contains_key("a_0"));
+		// Counted afresh by another cascade.
+		tpg.turn_pessimistic(&states);
+		tpg.committed_clean(&states);
+		assert!(tpg.pessimistic_states.read().unwrap().contains_key("a_0"));
+		tpg.committed_clean(&[String::from("b_0")]);
+		tpg.committed_clean(&states);
+		assert!(tpg.pessimistic_states.read().unwrap().is_empty());
+	}
 }
@@ -1,14 +1,19 @@
 use std::mem;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use crossbeam::atomic::AtomicCell;
 
 use crate::database::api::Database;
 use crate::database::simpledb;
 use crate::ds::transactions::{Txn, TXN_TEMPLATES};
-use crate::external::ffi::{self, TxnMessage};
-use crate::tpg::ev_node::{EvNode, EventStatus};
-use crate::utils::ShouldSyncCell;
+use crate::external::{ffi::{self, TxnMessage}, pipe};
+use crate::utils;
+use crate::monitor::{monitor, latency::{self, Stage}};
+use crate::worker::idle;
+use crate::config::CONFIG;
+use crate::tpg::ev_node::{Cascade, EvNode, EventStatus};
+use crate::tpg::ready_queue::EventSink;
+use crate::tpg::tpg::{Tpg, TPG};
 
 // Node linked to Construct TPG.
 #[derive(Debug)]
@@ -22,12 +27,11 @@ pub struct TxnNode{
 	 */
 	/*
 		read_from: This link used for marking parent txn as garbage and release at correct time.
-		- No Lock needed. Set after initialization. 
-		- Construct by construct_thread with add_link function.
+		- RwLock needed. Construct thread appends, also when dynamic events get linked. Committed parents set none.
+		- Construct by construct_thread with add_read_from function. One entry for each parent txn.
 		- Release when son is committed so its parents resources must be useless.
 	 */
-	pub read_from: Vec<ShouldSyncCell<Option<Arc<TxnNode>>>>,           			
-	pub read_from_index_map: HashMap<String, usize>,
+	pub read_from: RwLock<Vec<Option<Arc<TxnNode>>>>,
 	/*
 		read_by: This link used to reach its son and keep its son 
 		- Needs Lock. read_by updates dynamically. This link used for try_commit this txn.
@@ -37,11 +41,11 @@ pub struct TxnNode{
 	pub read_by: RwLock<Vec<Option<Arc<TxnNode>>>>,	        
 	/*
 		Cover: This link used to reach its write parent and keep its write parent. (Parent txn writing the same state.)
-		- No Lock needed. 
-		- Construct by construct_thread. Only being edited in construction.  
+		- RwLock needed. Construct thread inserts, also when dynamic events get linked. Multiple thread reads.
+		- Construct by construct_thread.
 		- Release when son is committed so its parents resources must be useless.
 	 */
-	pub cover: HashMap<String, ShouldSyncCell<Option<Arc<TxnNode>>>>,
+	pub cover: RwLock<HashMap<String, Option<Arc<TxnNode>>>>,
 	/*
 		Covered_by: This link used to reach its write Son and keep its write Son. (Son txn writing the same state.)
 		- RwLock needed. Single thread insert (Constructor), multiple thread write (Set none), multiple thread reads.
@@ -52,9 +56,25 @@ pub struct TxnNode{
 
 	// Meta.
 	pub status: AtomicCell<TxnStatus>,
-	pub ev_nodes: ShouldSyncCell<Vec<Arc<EvNode>>>, 	// Holds EvNode ownership. Consider switch to exclusive ownership.  Now the RWLock is used to setup loopback reference.
+	pub ev_nodes: RwLock<Vec<Arc<EvNode>>>, 	// Holds EvNode ownership. Consider switch to exclusive ownership.  Now the RWLock is used to setup loopback reference, and to replace dynamic events once resolved.
 	pub txn_req_id: u64,
+	pub type_idx: u16,
+	// Events of higher priority are served first by workers.
+	pub priority: u8,
 	pub ts: u64,
+	// Uncommitted transactions on the longest dependency chain ending here when linked, itself included.
+	pub depth: AtomicCell<u32>,
+	// Stages logged in history. Sampled once on creation.
+	pub traced: bool,
+	// When linked to TPG. 0 before. For latency histograms.
+	pub linked_at: AtomicCell<u64>,
+	// Why aborted. Set by the abortion that takes effect. Code 0 if not aborted.
+	pub abort_code: AtomicCell<u32>,
+	pub abort_message: RwLock<String>,
+	// Aborted by construct thread if not committed by then.
+	pub deadline: Option<u64>,
+	// Message to deposit again on abortion. Only kept when the template has retry policy.
+	retry_msg: Option<TxnMessage>,
 
 	// State count
 	unfinished_events: AtomicCell<u16>,   // When WAITING.
@@ -70,6 +90,13 @@ pub enum TxnStatus {
 	ABORTED,
 }
 
+// TxnNodes alive, till released by drop. Exported by the monitor.
+pub static LIVE_TXN_NODES: AtomicCell<i64> = AtomicCell::new(0);
+// TxnNodes still WAITING, neither aborted nor committed.
+pub static WAITING_TXNS: AtomicCell<i64> = AtomicCell::new(0);
+// Longest dependency chain of transactions linked since the monitor took it last time.
+pub static LONGEST_CHAIN: AtomicCell<u32> = AtomicCell::new(0);
+
 impl Drop for TxnNode {
 	// When reference goes to 0, Drop happens.
 	/*
@@ -83,13 +110,16 @@ impl Drop for TxnNode {
 			release versoin should just increase the start cursor by 1 to finish releasing.
 	 */
 	fn drop(&mut self) {
+		LIVE_TXN_NODES.fetch_sub(1);
 		// TODO. A waiting txn hitted here.
 		debug_assert!(self.status.load() == TxnStatus::COMMITED); // Aborted becomes COMMITTED in the end.
-		debug_assert!(self.read_from.iter().all(|tn| tn.read().is_none())
-						&& self.cover.iter().all(|(_k, v)| v.read().is_none())
+		debug_assert!(self.read_from.read().unwrap().iter().all(|tn| tn.is_none())
+						&& self.cover.read().unwrap().iter().all(|(_k, v)| v.is_none())
 					);
-		self.ev_nodes.write().iter().for_each(|en| {
-			simpledb::DB.get().unwrap().release_version("default",&en.write, self.ts);
+		self.ev_nodes.write().unwrap().iter().for_each(|en| {
+			en.writes.iter().for_each(|write| {
+				simpledb::DB.get().unwrap().release_version("default", write, self.ts);
+			});
 		});
 	}	
 }
@@ -100,37 +130,52 @@ impl TxnNode{
 	 */
 	pub fn from_message(mut msg: TxnMessage) -> Option<Arc<Self>> {
 		let tpl = &TXN_TEMPLATES.get().unwrap()[msg.type_idx as usize];
-		let mut index_map = HashMap::new();
-		let mut read_from_array = Vec::new();
-		tpl.es.iter().enumerate().for_each(|(e_idx, e)| 
-			e.reads.iter().enumerate().for_each(|(r_idx, state)| {
-				index_map.insert(format!("{}_{}", state, msg.reads_idx[e_idx][r_idx]), read_from_array.len());
-				read_from_array.push(ShouldSyncCell::new(None));
-			})
-		);
+		if msg.reads_idx.len() < tpl.es.len() || msg.write_idx.len() < tpl.es.len() 
+			|| tpl.es.iter().enumerate().any(|(idx, e)| msg.reads_idx[idx].len() < e.reads.len()) 
+			|| tpl.es.iter().enumerate().any(|(idx, e)| e.has_write && !e.extra_writes.is_empty()
+				&& msg.extra_writes_idx.get(idx).is_none_or(|w| w.len() < e.extra_writes.len())) {
+			return None
+		}
+		let writes = write_keys(tpl, &msg);
+		let inputs = input_keys(tpl, &msg);
+		if inputs_misrouted(tpl, &msg, &writes, &inputs) {
+			return None
+		}
+		// Retries keep the deadline of the first attempt.
+		msg.deadline_ns = msg.deadline_ns.or(tpl.timeout_ns.map(|t| msg.ts + t));
+		let retry_msg = tpl.retry.as_ref().map(|_| msg.clone());
+		LIVE_TXN_NODES.fetch_add(1);
+		WAITING_TXNS.fetch_add(1);
 		let ta = Arc::new(TxnNode{
-				read_from: read_from_array,
-				read_from_index_map: index_map,
+				read_from: RwLock::new(Vec::new()), // Empty and to construct.
 				read_by: RwLock::new(Vec::new()), // Empty and to construct.
-				cover: tpl.es
-					.iter().enumerate().filter(|(_, e)| e.has_write )
-					.map(|(idx, e)| (format!("{}_{}", e.write, msg.write_idx[idx]), ShouldSyncCell::new(None)))
-					.collect(),
+				cover: RwLock::new(HashMap::new()),
 				covered_by: RwLock::new(HashMap::new()),
 
 				status: AtomicCell::new(TxnStatus::WAITING),
-				ev_nodes: ShouldSyncCell::new(Vec::new()),
+				ev_nodes: RwLock::new(Vec::new()),
 				txn_req_id: msg.txn_req_id,
+				type_idx: msg.type_idx,
+				priority: msg.priority,
 
 				ts: msg.ts,
+				depth: AtomicCell::new(1),
+				traced: monitor::sampled(msg.txn_req_id),
+				linked_at: AtomicCell::new(0),
+				abort_code: AtomicCell::new(0),
+				abort_message: RwLock::new(String::new()),
+				deadline: msg.deadline_ns,
+				retry_msg,
 				// uncommitted_parents: AtomicCell::new(0),
 				unfinished_events: AtomicCell::new(tpl.es.len() as u16),
 			});
-		if msg.reads_idx.len() < tpl.es.len() || msg.write_idx.len() < tpl.es.len() {
-			return None
-		}
 		let mut ev_nodes = Vec::new();
 		for (idx, en) in tpl.es.iter().enumerate() {
+			// Keys of dynamic events are unknown yet. Hold the place till resolved.
+			if en.key_from.is_some() {
+				ev_nodes.push(Arc::new(EvNode::unresolved(en, idx as i32, Arc::downgrade(&ta.clone()), inputs[idx].clone())));
+				continue
+			}
 			ev_nodes.push(
 				Arc::new(
 					EvNode::from_template(
@@ -138,12 +183,13 @@ impl TxnNode{
 						idx as i32,
 						Arc::downgrade(&ta.clone()),
 						mem::take(&mut msg.reads_idx[idx]),
-						msg.write_idx[idx],
+						writes[idx].clone(),
+						inputs[idx].clone(),
 					)?
 				)
 			)
 		}
-		let mut ev_nodes_place = ta.ev_nodes.write();
+		let mut ev_nodes_place = ta.ev_nodes.write().unwrap();
 		*ev_nodes_place = ev_nodes;
 		drop(ev_nodes_place);
 		// if CONFIG.read().unwrap().debug_mode {
@@ -153,7 +199,23 @@ impl TxnNode{
 	}
 
 	// set links on tpg for eventNodes. This function is dangerous. Only call from construct thread.
-	pub fn set_links(&self, tb: &RwLock<HashMap<String, Option<(Weak<EvNode>,Arc<TxnNode>)>>>) {
+	// Dynamic events are skipped. They are linked by resolve_dynamic once their keys are known.
+	pub fn set_links(&self, tpg: &Tpg) {
+		debug_assert!(self.status.load() == TxnStatus::WAITING);
+		self.ev_nodes.read().unwrap().iter()
+			.filter(|en| en.key_from.is_none())
+			.for_each(|en| self.link_event(en, tpg));
+		let depth = 1 + self.read_from.read().unwrap().iter().flatten()
+			.chain(self.cover.read().unwrap().values().flatten())
+			.filter(|p| p.status.load() != TxnStatus::COMMITED)
+			.map(|p| p.depth.load())
+			.max().unwrap_or(0);
+		self.depth.store(depth);
+		LONGEST_CHAIN.fetch_max(depth);
+	}
+
+	// set links on tpg for one eventNode. This function is dangerous. Only call from construct thread.
+	fn link_event(&self, en: &Arc<EvNode>, tpg: &Tpg) {
 		/*
 			add_dependency executes steps in sequence to ensure safety:
 			1. Find the parent. Append to self.read_from;
@@ -162,63 +224,119 @@ impl TxnNode{
 			4. Decide the evNode to go to queue or to be landed by node.
 			Unverified.
 		 */
-		debug_assert!(self.status.load() == TxnStatus::WAITING);
-		self.ev_nodes.read().iter().for_each(|en| {
-			debug_assert!(en.status.load() == EventStatus::CONSTRUCT); 
-			// Set read_from and parent read_by.
-			let last_modify_hashmap = tb.read().unwrap();
-			// Anyway, update.
-			en.reads.iter().enumerate().for_each(|(idx, state)|{
-				let last_option = last_modify_hashmap.get(state.as_str())
-					.unwrap_or_else(|| 
-						{panic!("State has no slot in last_modify_hashmap: {:?}", state)}
-					);
-				if last_option.is_some() {
-					// 	Find parent. Do have last.
-					let (last_en, last_tn) = last_option.as_ref().unwrap();
-					// TODO. Check if allocation.
-					// Set this event.read_from
-					let mut e = en.read_from[idx].write();
-					*e = Some(last_en.clone());
-					// Set read by for both parent evNode and txnNode.
-					last_en.upgrade().unwrap().add_read_by(en);
-					// Write to correspondent
-					let mut wr = self.read_from_by_state(state).write(); 
-					if (*wr).is_none() {
-						*wr = Some(last_tn.clone());
-					}
-				} else {
-					let mut e = en.read_from[idx].write();
-					*e = None;
-				}
-			});
-			drop(last_modify_hashmap);
-			if en.has_write {
-				let self_arc =  en.txn.upgrade().unwrap().clone();
-				// Set self.cover and parent self.covered_by.
-				let last_modify_hashmap = tb.read().unwrap();
-				let last_option = last_modify_hashmap.get(&en.write)
-					.unwrap_or_else(|| 
-						{panic!("State has no slot in last_modify_hashmap: {:?}", &en.write)}
-					);
-				if let Some((_, last_txn)) = last_option {
-					// Someone wrote. record and update list.
-					let mut re = self.cover[&en.write].write(); // Clone and remove later.
-					*re = Some(last_txn.clone());
-					last_txn.add_covered_by(&en.write, &self_arc);
+		let tb = &tpg.state_last_modify;
+		debug_assert!(en.status.load() == EventStatus::CONSTRUCT); 
+		// Set read_from and parent read_by.
+		let last_modify_hashmap = tb.read().unwrap();
+		// Anyway, update.
+		// Parents are linked after all read_from and merge_from set. Otherwise they could visit this node half-built.
+		let mut parents = Vec::new();
+		let pessimistic_states = tpg.pessimistic_states.read().unwrap();
+		en.reads.iter().enumerate().for_each(|(idx, state)|{
+			let last_option = last_modify_hashmap.get(state.as_str())
+				.unwrap_or_else(|| 
+					{panic!("State has no slot in last_modify_hashmap: {:?}", state)}
+				);
+			if last_option.is_some() {
+				// 	Find parent. Do have last.
+				let (last_en, _) = last_option.as_ref().unwrap();
+				// TODO. Check if allocation.
+				// Set this event.read_from
+				let mut e = en.read_from[idx].write();
+				*e = Some(last_en.clone());
+				// Held till the writer commits. Not for writers in the same txn, which could never commit before.
+				if pessimistic_states.contains_key(state) && !last_en.upgrade().unwrap().txn.ptr_eq(&en.txn) {
+					en.held[idx].store(true);
 				}
-				// Update to state_last_modify anyway.
-				/*
-					Here we assign a reference count to prevent the txn removed. 
-					Explicitly clone.
-				*/
-				drop(last_modify_hashmap);
-				let mut last_modify_hashmap = tb.write().unwrap();
-				last_modify_hashmap.insert(en.write.clone(), Some((Arc::downgrade(en),self_arc)));
+				parents.push(last_en.clone());
+			} else {
+				let mut e = en.read_from[idx].write();
+				*e = None;
+			}
+		});
+		// Merge events since the last plain write are not reached by read_from. The last modifier is.
+		let mut pending_merges = tpg.state_pending_merges.write().unwrap();
+		let mut merge_from = Vec::new();
+		en.reads.iter().for_each(|state| {
+			if let Some(pending) = pending_merges.get_mut(state) {
+				pending.retain(|p| p.upgrade().is_some_and(
+					|p| p.txn.upgrade().is_some_and(|tn| tn.status.load() != TxnStatus::COMMITED)
+				));
+				let last = last_modify_hashmap.get(state.as_str())
+					.and_then(|l| l.as_ref().map(|(last_en, _)| last_en.as_ptr()));
+				merge_from.extend(pending.iter()
+					.filter(|p| Some(p.as_ptr()) != last)
+					.map(|p| (p.clone(), AtomicCell::new(false)))
+				);
+			}
+		});
+		drop(pessimistic_states);
+		parents.extend(merge_from.iter().map(|(p, _)| p.clone()));
+		*en.merge_from.write() = merge_from;
+		// Set read by for both parent evNode and txnNode.
+		parents.iter().for_each(|p| {
+			let p = p.upgrade().unwrap();
+			self.add_read_from(&p.txn.upgrade().unwrap());
+			p.add_read_by(en);
+		});
+		drop(last_modify_hashmap);
+		// Reserve versions in ts order. Merge events write back out of order.
+		en.reserve_slot();
+		en.writes.iter().for_each(|write| {
+			let self_arc =  en.txn.upgrade().unwrap().clone();
+			// Set self.cover and parent self.covered_by.
+			let last_modify_hashmap = tb.read().unwrap();
+			let last_option = last_modify_hashmap.get(write)
+				.unwrap_or_else(|| 
+					{panic!("State has no slot in last_modify_hashmap: {:?}", write)}
+				);
+			match last_option {
+				// Someone wrote. record and update list. Not for events in the same transaction.
+				Some((_, last_txn)) if !Arc::ptr_eq(last_txn, &self_arc) => {
+					self.cover.write().unwrap().insert(write.clone(), Some(last_txn.clone()));
+					last_txn.add_covered_by(write, &self_arc);
+				},
+				_ => {},
+			}
+			let pending = pending_merges.entry(write.clone()).or_default();
+			if en.merge.is_none() {
+				pending.clear();
 			}
+			pending.push(Arc::downgrade(en));
+			// Update to state_last_modify anyway.
+			/*
+				Here we assign a reference count to prevent the txn removed. 
+				Explicitly clone.
+			*/
+			drop(last_modify_hashmap);
+			let mut last_modify_hashmap = tb.write().unwrap();
+			last_modify_hashmap.insert(write.clone(), Some((Arc::downgrade(en),self_arc)));
 		});
 	}
 
+	/*
+		Link the dynamic event once the key is known. Only call from construct thread.
+		The placeholder is replaced by a new node reading and writing the resolved row.
+	 */
+	pub fn resolve_dynamic(&self, idx: usize, row: usize, tpg: &Tpg) -> Arc<EvNode> {
+		let tpl = &TXN_TEMPLATES.get().unwrap()[self.type_idx as usize].es[idx];
+		let placeholder = self.ev_nodes.read().unwrap()[idx].clone();
+		debug_assert!(placeholder.key_from.is_some() && placeholder.status.load() == EventStatus::CONSTRUCT);
+		let en = Arc::new(
+			EvNode::from_template(
+				tpl,
+				idx as i32,
+				placeholder.txn.clone(),
+				vec![row; tpl.reads.len()],
+				tpl.all_writes().iter().map(|w| format!("{}_{}", w, row)).collect(),
+				placeholder.reads.clone(), // Placeholder holds keys of inputs.
+			).expect("bug. resolved event mismatches template."),
+		);
+		self.ev_nodes.write().unwrap()[idx] = en.clone();
+		self.link_event(&en, tpg);
+		en
+	}
+
 	// Safe function. Only happens to WAITING txn.
 	pub fn event_accepted_no_unfinished(&self) -> bool {
 		debug_assert!(self.status.load() == TxnStatus::WAITING);
@@ -244,13 +362,26 @@ impl TxnNode{
 		self.unfinished_events.fetch_add(1);
 	}
 
-	// Add_read_by adds txn that reads my result.
-	pub fn read_from_by_state(&self, state: &str) -> &ShouldSyncCell<Option<Arc<TxnNode>>>{
-		&self.read_from[self.read_from_index_map[state]]
+	// Add_read_from adds parent txn whose result I read. Not for myself, nor twice.
+	pub fn add_read_from(&self, parent: &Arc<TxnNode>) {
+		if std::ptr::eq(Arc::as_ptr(parent), self) {
+			return
+		}
+		let mut read_from = self.read_from.write().unwrap();
+		if !read_from.iter().any(|tn| tn.as_ref().is_some_and(|tn| Arc::ptr_eq(tn, parent))) {
+			read_from.push(Some(parent.clone()));
+		}
 	}
 
 	pub fn no_waiting(&self) -> bool {
-		self.read_from.iter().all(|tn| tn.read().is_none())
+		self.read_from.read().unwrap().iter().all(|tn| tn.is_none())
+	}
+
+	// Merge events do not read the key they write. Commit order of the same key is kept by cover.
+	pub fn no_uncommitted_cover(&self) -> bool {
+		self.cover.read().unwrap().iter().all(|(_, tn)| 
+			tn.as_ref().is_none_or(|tn| tn.status.load() == TxnStatus::COMMITED)
+		)
 	}
 
 	// Safe function.
@@ -260,16 +391,15 @@ impl TxnNode{
 				|| self.status.load() == TxnStatus::ABORTED // Aborted transaction also passes commitment to descedants.
 		);
 		// Find father himself in the son's reading list. To decrease the uncommitted parents count.
-		if !self.read_from.iter().any(|tn_op	| {
-			if tn_op.read().as_ref().is_some_and(|tn| tn.ts == father.ts ) {
+		let mut found = false;
+		self.read_from.write().unwrap().iter_mut().for_each(|tn_op| {
+			if tn_op.as_ref().is_some_and(|tn| tn.ts == father.ts ) {
 				// Set to None.
-				let mut w = tn_op.write();
-				*w = None;
-				true
-			} else {
-				false
+				*tn_op = None;
+				found = true;
 			}
-		}) {
+		});
+		if !found {
 			panic!("committed father not in son read_from.");
 		}
 		// debug_assert!(self.uncommitted_parents.load() > 0);
@@ -298,11 +428,20 @@ impl TxnNode{
 		// 	TxnStatus::WAITING => {},
 		// 	_ => return false,
 		// }
-		if !(self.unfinished_events.load() == 0 && self.no_waiting()) {
+		if !(self.unfinished_events.load() == 0 && self.no_waiting() && self.no_uncommitted_cover()) {
 			return false
 		}
 		// Having decide this txn can be commmitted.
-		self.status.store(TxnStatus::COMMITED);
+		let last = self.status.swap(TxnStatus::COMMITED);
+		let aborted = last == TxnStatus::ABORTED;
+		if last == TxnStatus::WAITING {
+			WAITING_TXNS.fetch_sub(1);
+		}
+		if !aborted {
+			latency::record(self.type_idx, Stage::IngressToCommit, utils::current_time_ns().saturating_sub(self.ts));
+			let tpg = TPG.get().unwrap();
+			self.ev_nodes.read().unwrap().iter().for_each(|e| tpg.committed_clean(&e.writes));
+		}
 
 		// Continue to apply other changes.
 		
@@ -315,23 +454,34 @@ impl TxnNode{
 		// 	let mut wp = parent.write();
 		// 	*wp = None;
 		// });
-		self.cover.iter().for_each(|(_, w_parent)|{
+		self.cover.write().unwrap().iter_mut().for_each(|(_, w_parent)|{
 			debug_assert!({
-				w_parent.read().is_none() 
-				 || w_parent.read().as_ref().unwrap().status.load() == TxnStatus::COMMITED
+				w_parent.is_none() 
+				 || w_parent.as_ref().unwrap().status.load() == TxnStatus::COMMITED
 			}); // Could not be waiting or garbaged.
-			let mut wp = w_parent.write();
-			*wp = None;
+			*w_parent = None;
 		});
 
 		debug_assert!({
-			self.read_from.iter().all(|state| state.read().is_none()) 
-			&& self.cover.iter().all(|(_, state)| state.read().is_none()) 
+			self.read_from.read().unwrap().iter().all(|state| state.is_none()) 
+			&& self.cover.read().unwrap().iter().all(|(_, state)| state.is_none()) 
 		});
 
-		// Inform the runtime that the txn has been processed.
+		// Inform the runtime that the txn has been processed. Retried txn informs when the last attempt finished.
 		// TODO. Judge by self.status to reply ILLEGAL, SUCCESS, or what.
-		ffi::txn_finished_sign(self.txn_req_id);
+		if !aborted {
+			ffi::txn_finished_sign(self.txn_req_id, None);
+		} else if !self.retry() {
+			ffi::txn_finished_sign(self.txn_req_id, Some(&ffi::AbortReason {
+				code: self.abort_code.load(),
+				message: self.abort_message.read().unwrap().clone(),
+			}));
+		}
+
+		// Events held till this commitment could go on now.
+		self.ev_nodes.read().unwrap().iter()
+			.filter(|en| en.status.load() != EventStatus::CONSTRUCT)
+			.for_each(|en| en.release_held(&TPG.get().unwrap().ready_queue_in));
 
 		// Perform commitment on dependent sons. This step should be the last part of commitment, since we need commitment to be in order.
 		for son in self.read_by.read().unwrap().iter() {
@@ -340,12 +490,52 @@ impl TxnNode{
 			// We do not think a network situation would allow so much txn linked to cause stack overflow. So recursion here.
 			node.try_commit();
 		}
+		// Writers of the same key without reading (merge events) are only ordered by cover.
+		for node in self.covered_by.read().unwrap().values().flatten() {
+			if node.status.load() != TxnStatus::COMMITED {
+				node.try_commit();
+			}
+		}
 
 		true
 	}
 
-	// Transaction abortion. 
-	pub fn abort(&self) {
+	// Deposit this aborted txn again if the policy allows. Returns whether it's retried.
+	fn retry(&self) -> bool {
+		let msg = match &self.retry_msg {
+			Some(msg) => msg,
+			None => return false,
+		};
+		let policy = TXN_TEMPLATES.get().unwrap()[self.type_idx as usize].retry.as_ref().unwrap();
+		let backoff = match policy.backoff(msg.attempt + 1, self.abort_code.load()) {
+			Some(backoff) => backoff,
+			None => return false,
+		};
+		let mut msg = msg.clone();
+		msg.attempt += 1;
+		pipe::RETRY_IN.get().unwrap().send((utils::current_time_ns() + backoff, msg)).unwrap();
+		idle::wake_all();
+		true
+	}
+
+	// Sons of aborted events read the versions restored. Enqueue those ready. Call after abort.
+	pub fn enqueue_sons_of_aborted(&self, pipe: &dyn EventSink) {
+		self.ev_nodes.read().unwrap().iter()
+			.filter(|aborted_evn| aborted_evn.status.load() != EventStatus::CONSTRUCT) // Dynamic events never linked.
+			.for_each(|aborted_evn| {
+				debug_assert!(aborted_evn.status.load() == EventStatus::ABORTED);
+				// Claimed for the calling thread. Hand over to the global queue.
+				if let Some(n) = aborted_evn.get_next_option_push_others_ready(pipe) {
+					// Reset by parents meanwhile otherwise. They would reschedule it.
+					if n.status.compare_exchange(EventStatus::CLAIMED, EventStatus::INQUEUE).is_ok() {
+						pipe.push(n);
+					}
+				}
+			});
+	}
+
+	// Transaction abortion. Returns the cascade if this call takes effect, namely it's the first abortion.
+	pub fn abort(&self, reason: ffi::AbortReason) -> Option<Cascade> {
 		/*
 			This function: 
 			- Only happens in waiting transactions. Triggered by evNode that arose abortion.
@@ -358,23 +548,89 @@ impl TxnNode{
 		 */
 		// TODO. We may remove this judgement if the following operation is redoable.
 		match self.status.compare_exchange(TxnStatus::WAITING, TxnStatus::ABORTED){
-			Ok(_) => {}
+			Ok(_) => { WAITING_TXNS.fetch_sub(1); },
 			Err(original) => {
 				debug_assert!(original == TxnStatus::ABORTED);
-				return; // Another operator in this txn has set it to be aborted.
+				return None; // Another operator in this txn has set it to be aborted.
 			}
 		}
+		self.abort_code.store(reason.code);
+		*self.abort_message.write().unwrap() = reason.message;
+		// Events under construction are aborted by construct thread once linked.
+		let threshold = CONFIG.read().unwrap().cascade_threshold;
+		let mut cascade = Cascade::default();
 		self.ev_nodes
-			.read().iter().for_each(
-				|e| e.abort()
-			);
+			.read().unwrap().iter()
+			.filter(|e| e.status.load() != EventStatus::CONSTRUCT)
+			.for_each(|e| {
+				let c = e.abort();
+				// Later readers of these states wait for the writer to commit from now on.
+				if threshold != 0 && c.fanout > threshold {
+					TPG.get().unwrap().turn_pessimistic(&e.writes);
+					cascade.pessimistic += 1;
+				}
+				cascade.depth = cascade.depth.max(c.depth);
+				cascade.fanout += c.fanout;
+			});
 		self.unfinished_events.store(0);
 		// Abortion txn also commits. Since the later transactions dates back to check if commitable.
 		self.try_commit();
+		Some(cascade)
+	}
+
+	/*
+		Abort a txn never linked to TPG, e.g. held by the construct thread past its deadline.
+		Nothing of it is in TPG or the database, so it's done at once. Returns false if not WAITING.
+	 */
+	pub fn abort_unlinked(&self, reason: ffi::AbortReason) -> bool {
+		debug_assert!(self.linked_at.load() == 0);
+		if self.status.compare_exchange(TxnStatus::WAITING, TxnStatus::COMMITED).is_err() {
+			return false
+		}
+		WAITING_TXNS.fetch_sub(1);
+		// No versions to release on drop.
+		self.ev_nodes.write().unwrap().clear();
+		self.abort_code.store(reason.code);
+		*self.abort_message.write().unwrap() = reason.message.clone();
+		if !self.retry() {
+			ffi::txn_finished_sign(self.txn_req_id, Some(&reason));
+		}
+		true
 	}
 
 }
 
+// Write keys of each event. Template write first, then extra writes.
+fn write_keys(tpl: &Txn, msg: &TxnMessage) -> Vec<Vec<String>> {
+	tpl.es.iter().enumerate().map(|(idx, e)| 
+		e.all_writes().iter().enumerate().map(|(w_idx, w)| {
+			let row = if w_idx == 0 { msg.write_idx[idx] } else { msg.extra_writes_idx[idx][w_idx - 1] };
+			format!("{}_{}", w, row)
+		}).collect()
+	).collect()
+}
+
+// Results of earlier events in the same transaction are read by their write keys.
+fn input_keys(tpl: &Txn, msg: &TxnMessage) -> Vec<Vec<String>> {
+	tpl.es.iter().map(|e| 
+		e.inputs.iter().map(|&i| format!("{}_{}", tpl.es[i].write, msg.write_idx[i])).collect()
+	).collect()
+}
+
+/*
+	Inputs are linked to the last writer of their key. It's not the event named if:
+	- an event in between writes the same key, or
+	- the event reads the key itself, which would also link it twice.
+	Dynamic events are ruled out by the template check, as their rows are unknown here.
+ */
+fn inputs_misrouted(tpl: &Txn, msg: &TxnMessage, writes: &[Vec<String>], inputs: &[Vec<String>]) -> bool {
+	tpl.es.iter().enumerate().any(|(idx, e)| e.inputs.iter().zip(inputs[idx].iter()).any(|(&i, key)|
+		writes[i + 1..idx].iter().any(|w| w.contains(key))
+			|| (e.key_from.is_none() && e.reads.iter().zip(msg.reads_idx[idx].iter())
+				.any(|(r, row)| format!("{}_{}", r, row) == *key))
+	))
+}
+
 // impl Clone for TxnNode{
 // 	fn clone(&self) -> Self {
 // 		TxnNode {
@@ -386,3 +642,43 @@ impl TxnNode{
 // 		}
 // 	}
 // }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Event 0 writes "a", event 1 writes "a" and event 2 reads the result of event 0 besides "a".
+	fn template() -> Txn {
+		let mut tpl = Txn {
+			es: serde_json::from_value(serde_json::json!([
+				{ "reads": [], "write": "a", "has_write": true },
+				{ "reads": [], "write": "a", "has_write": true },
+				{ "reads": ["a"], "write": "", "has_write": false, "inputs": [0] },
+			])).unwrap(),
+			..Default::default()
+		};
+		tpl.process_txn();
+		tpl
+	}
+
+	fn misrouted(tpl: &Txn, write_idx: Vec<usize>, reads_idx: Vec<Vec<usize>>) -> bool {
+		let msg: TxnMessage = serde_json::from_value(serde_json::json!({
+			"type_idx": 0, "ts": 1, "txn_req_id": 1, "reads_idx": reads_idx, "write_idx": write_idx,
+		})).unwrap();
+		inputs_misrouted(tpl, &msg, &write_keys(tpl, &msg), &input_keys(tpl, &msg))
+	}
+
+	#[test]
+	fn test_input_written_in_between() {
+		let tpl = template();
+		assert!(misrouted(&tpl, vec![1, 1, 0], vec![vec![0], vec![0], vec![2]]));
+		assert!(!misrouted(&tpl, vec![1, 2, 0], vec![vec![0], vec![0], vec![2]]));
+	}
+
+	#[test]
+	fn test_input_read_itself() {
+		let tpl = template();
+		assert!(misrouted(&tpl, vec![1, 2, 0], vec![vec![0], vec![0], vec![1]]));
+		assert!(!misrouted(&tpl, vec![1, 2, 0], vec![vec![0], vec![0], vec![3]]));
+	}
+}
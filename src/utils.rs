@@ -1,7 +1,4 @@
 use std::{sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard}, time::{SystemTime, UNIX_EPOCH}};
-use lazy_static::lazy_static;
-
-use core_affinity::CoreId;
 
 
 // Some data structure in our system guarantees thread safety by itself. Mark it to be "Will be no conflict" and detect bug.
@@ -23,6 +20,10 @@ impl<T> ShouldSyncCell<T> {
     pub fn read(&self) -> RwLockReadGuard<'_, T> {
         self.body.try_read().unwrap()
     }
+    // Not panic. For inspection from other threads, e.g. debug dumps.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.body.try_read().ok()
+    }
     pub fn write(&self) -> RwLockWriteGuard<'_, T> {
         self.body.try_write().unwrap()
     }
@@ -42,21 +43,6 @@ pub fn ns_to_system_time(nanoseconds: u64) -> SystemTime {
     UNIX_EPOCH + duration
 }
 
-lazy_static! {
-    static ref AVAILABLE_CPUS: Vec<CoreId> = {
-        core_affinity::get_core_ids()
-            .expect("Failed to get core IDs")
-    };
-}
-
-pub fn bind_to_cpu_core(tid: usize){
-    let to_bind = AVAILABLE_CPUS[tid];
-    let res = core_affinity::set_for_current(to_bind);
-    if !res {
-        panic!("Bingding failed.")
-    }
-}
-
 pub fn report_cpu_core(name: &str){
     // Retrieve the current thread id
     let core_id = affinity::get_thread_affinity().unwrap();
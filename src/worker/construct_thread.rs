@@ -1,6 +1,9 @@
 use crate::external::{ffi, pipe};
-use crate::monitor::monitor;
+use crate::worker::idle::Idler;
+use crate::monitor::monitor::{self, TraceStage};
+use crate::monitor::latency::{self, Stage};
 use crate::tpg::tpg::TPG;
+use crate::ds::transactions::TXN_TEMPLATES;
 use crate::tpg::{
 	txn_node::*,
 	ev_node::*,
@@ -8,12 +11,16 @@ use crate::tpg::{
 use crate::utils;
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::sync::mpsc::TryRecvError;
 
 use crate::config::CONFIG;
 
 // TODO. Use channel to sync shutdown.
 pub static mut GRACEFUL_SHUTDOWN: bool = false;
+// Stop taking new transactions, e.g. by the admin socket. Those in TPG still run.
+pub static INGRESS_PAUSED: AtomicBool = AtomicBool::new(false);
 
 // This worker thread constructs TPG streamingly.
 // TODO. Slab memory allocation to reduce the allocation time.
@@ -26,34 +33,62 @@ pub fn construct_thread(_: i16){
 	let mut timeout_waiting_queue = Vec::<TxnNode>::new();
 
 	let txn_msg_queue = pipe::init();
+	let retry_queue = pipe::init_retry();
+	let mut pending_retries = Vec::<(u64, ffi::TxnMessage)>::new(); // Waiting for backoff.
+	let mut deadlines = BTreeMap::<(u64, u64), Weak<TxnNode>>::new(); // Keyed by deadline and txn_req_id.
+	let mut parked = Parked::default();
+	// Largest ts taken in. Each txn taken in gets a larger one, as ts orders versions of each state.
+	let mut last_ts = 0;
+	let mut idler = Idler::new(0);
 
 	// TODO. Graceful shutdown.
 	loop { // Outer loop. For each valid transaction.
 		if unsafe { GRACEFUL_SHUTDOWN } == true{
 			println!("Construct thread shutdown. ");
+			parked.abort_all();
 			break
 		}
+		parked.advance();
 		let tn;
 		// Message receiver.
 		loop { // Inner loop. Take out txn from queue and order it. Take out latest transacation each time.
-			let new_txn_msg: ffi::TxnMessage;
-			match txn_msg_queue.try_recv() {
-				Ok(res) => {new_txn_msg = res},
-				Err(err) => match err {
-					TryRecvError::Empty => {
-						// Continue if the receiver is empty
-						continue;
-					}
-					_ => {
-						// Panic for any other error
-						panic!("Error receiving message: {:?}", err);
-					}
-				},
-			};
+			let mut new_txn_msg: ffi::TxnMessage;
+			// Each time, as the pipe may never drain under load.
+			abort_expired(&mut deadlines, &mut parked);
+			if INGRESS_PAUSED.load(Ordering::Relaxed) {
+				if !parked.advance() {
+					idler.idle();
+				}
+				continue;
+			}
+			// Retried txns first. They are deposited with new timestamp, see ingress_ts.
+			pending_retries.extend(retry_queue.try_iter());
+			if let Some(pos) = pending_retries.iter().position(|(due, _)| *due <= utils::current_time_ns()) {
+				new_txn_msg = pending_retries.swap_remove(pos).1;
+				monitor::MONITOR.get().unwrap()[0].inc("txn.retry");
+			} else {
+				match txn_msg_queue.try_recv() {
+					Ok(res) => {new_txn_msg = res},
+					Err(err) => match err {
+						TryRecvError::Empty => {
+							if !parked.advance() {
+								idler.idle();
+							}
+							// Continue if the receiver is empty
+							continue;
+						}
+						_ => {
+							// Panic for any other error
+							panic!("Error receiving message: {:?}", err);
+						}
+					},
+				};
+			}
+			new_txn_msg.ts = ingress_ts(last_ts, new_txn_msg.ts);
 			if CONFIG.read().unwrap().verbose {
 				let op_tn = TxnNode::from_message(new_txn_msg.clone());
 				if op_tn.is_none(){
-					ffi::txn_finished_sign(new_txn_msg.txn_req_id); // TODO. Inform illegal.
+					ffi::txn_finished_sign(new_txn_msg.txn_req_id, Some(&ffi::AbortReason::new(ffi::ABORT_ILLEGAL_REQUEST, "illegal request")));
 						println!("[DEBUG] invalid txn msg: {:?}", new_txn_msg);
 					continue
 				} else {
@@ -63,7 +98,7 @@ pub fn construct_thread(_: i16){
 				let req = new_txn_msg.txn_req_id;
 				let op_tn = TxnNode::from_message(new_txn_msg);
 				if op_tn.is_none(){
-					ffi::txn_finished_sign(req);
+					ffi::txn_finished_sign(req, Some(&ffi::AbortReason::new(ffi::ABORT_ILLEGAL_REQUEST, "illegal request")));
 					continue
 				} else {
 					tn = op_tn.unwrap();
@@ -85,70 +120,274 @@ pub fn construct_thread(_: i16){
 
 			break;
 		}
+		idler.busy();
+		last_ts = last_ts.max(tn.ts);
+		// From now on, whether held or linked.
+		if let Some(deadline) = tn.deadline {
+			deadlines.insert((deadline, tn.txn_req_id), Arc::downgrade(&tn));
+		}
+
+		if parked.holds(&tn) {
+			monitor::MONITOR.get().unwrap()[0].inc("txn.held");
+			parked.txns.push_back(tn);
+			continue
+		}
+		link(&tn);
+		resolve_dynamic_events(&tn);
+		if tn.status.load() == TxnStatus::WAITING && has_unresolved(&tn) {
+			parked.blocked.extend(pending_states(&tn));
+			parked.txns.push_back(tn);
+		}
+	}	
+	// Graceful Shutdown.
+}
+
+
+// Timestamp of a txn taken in. Retries keep the ts of their first attempt, so they get one above the
+// latest. Those queued behind a retry are bumped above it in turn. Increasing either way.
+fn ingress_ts(last_ts: u64, ts: u64) -> u64 {
+	ts.max(last_ts + 1)
+}
+
+// Link a txn into TPG, and enqueue its events ready. Dynamic events are left unresolved.
+fn link(tn: &Arc<TxnNode>) {
+	monitor::trace_at(0, tn, TraceStage::DispatchedFromVnf, tn.ts, None);
+	monitor::trace(0, tn, TraceStage::SortingDone, None);
 
-		if CONFIG.read().unwrap().monitor_enabled {
-			monitor::MONITOR.get().unwrap()[0].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},dispatched_from_vnf,{}", tn.txn_req_id, tn.ts),
-			});
+	// Set link between events nodes to later ones.
+	// Set link between this txn and its parents.
+	tn.set_links(TPG.get().unwrap());
+	let linked_at = utils::current_time_ns();
+	tn.linked_at.store(linked_at);
+	latency::record(tn.type_idx, Stage::IngressToLink, linked_at.saturating_sub(tn.ts));
 
-			monitor::MONITOR.get().unwrap()[0].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},sorting_done,{}", tn.txn_req_id, utils::current_time_ns()),
-			});
+	monitor::trace(0, tn, TraceStage::LinkedToTpg, None);
+
+	// if ready, into ready_queue. Else will be visited by ancestors.
+	let ev_nodes = tn.ev_nodes.read().unwrap().clone();
+	ev_nodes.iter()
+		.filter(|ev_node| ev_node.key_from.is_none()) // Dynamic events wait for their keys.
+		.for_each(|ev_node| enqueue_if_ready(tn, ev_node));
+}
+
+// Dynamic event whose key is not known yet.
+fn is_unresolved(ev_node: &EvNode) -> bool {
+	ev_node.key_from.is_some() && ev_node.status.load() == EventStatus::CONSTRUCT
+}
+
+fn has_unresolved(tn: &TxnNode) -> bool {
+	tn.ev_nodes.read().unwrap().iter().any(|ev_node| is_unresolved(ev_node))
+}
+
+/*
+	Link dynamic events whose keys are final, in order. The key comes from the result of an earlier event
+	in this txn. It's final when that event accepted and all parent txns committed, since nothing could reset
+	it except abortion of this txn.
+ */
+fn resolve_dynamic_events(tn: &Arc<TxnNode>) {
+	let ev_nodes = tn.ev_nodes.read().unwrap().clone();
+	for ev_node in ev_nodes.iter().filter(|ev_node| is_unresolved(ev_node)) {
+		if tn.status.load() != TxnStatus::WAITING {
+			return // Aborted. The rest are never linked.
+		}
+		let source = tn.ev_nodes.read().unwrap()[ev_node.key_from.unwrap()].clone();
+		if !(source.status.load() == EventStatus::ACCEPTED && tn.no_waiting()) {
+			return
 		}
+		let row = source.key_result.load().unwrap();
+		if row >= CONFIG.read().unwrap().max_state_records {
+			// No such row. Abort as UDF asked.
+			monitor::MONITOR.get().unwrap()[0].inc("evnode.dynamic_key_invalid");
+			if let Some(cascade) = tn.abort(ffi::AbortReason::new(ffi::ABORT_INVALID_KEY, "row out of range")) {
+				monitor::record_abort(0, tn, ffi::ABORT_INVALID_KEY, cascade);
+			}
+			return
+		}
+		let resolved = tn.resolve_dynamic(ev_node.idx as usize, row, TPG.get().unwrap());
+		enqueue_if_ready(tn, &resolved);
+	}
+}
 
-		// Set link between events nodes to later ones.
-		// Set link between this txn and its parents.
-		tn.set_links(&TPG.get().unwrap().state_last_modify);
+// Name of the state of a key, without the row.
+fn state_of(key: &str) -> &str {
+	key.rsplit_once('_').map_or(key, |(state, _)| state)
+}
 
-		if CONFIG.read().unwrap().monitor_enabled {
-			monitor::MONITOR.get().unwrap()[0].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},linked_to_tpg,{}", tn.txn_req_id, utils::current_time_ns()),
-			});
+// States, by name, a txn could still touch when linked later. Any row, since keys of dynamic events are unknown.
+fn pending_states(tn: &TxnNode) -> HashSet<String> {
+	let linked = tn.linked_at.load() != 0;
+	let tpl = &TXN_TEMPLATES.get().unwrap()[tn.type_idx as usize];
+	let mut states = HashSet::new();
+	for ev_node in tn.ev_nodes.read().unwrap().iter() {
+		let unresolved = is_unresolved(ev_node);
+		if linked && !unresolved {
+			continue
 		}
+		if unresolved {
+			let event = &tpl.es[ev_node.idx as usize];
+			states.extend(event.reads.iter().chain(event.all_writes()).cloned());
+		}
+		// Keys of inputs for unresolved ones.
+		states.extend(ev_node.reads.iter().chain(ev_node.writes.iter()).map(|key| state_of(key).to_string()));
+	}
+	states
+}
+
+/*
+	Txns with dynamic events not linked yet, and later ones held back behind them, in ts order.
+	TPG of a state is built in ts order. A dynamic event could touch any row of its states, so later txns
+	touching states of the same name are held till it's linked. Others are linked as they come.
+ */
+#[derive(Default)]
+struct Parked {
+	txns: VecDeque<Arc<TxnNode>>,
+	// Pending states of all parked txns.
+	blocked: HashSet<String>,
+}
 
-		// if ready, into ready_queue. Else will be visited by ancestors.
-		tn.ev_nodes.read().iter().for_each(|ev_node| {
-			ev_node.status.store(EventStatus::WAITING); // Possibly claimed during counting.
+impl Parked {
+	fn holds(&mut self, tn: &TxnNode) -> bool {
+		if self.txns.is_empty() {
+			return false
+		}
+		let states = pending_states(tn);
+		if states.is_disjoint(&self.blocked) {
+			return false
+		}
+		self.blocked.extend(states);
+		true
+	}
 
-			ev_node.read_from.iter().enumerate().for_each(|(idx, last)|{
-				if last.read().is_none() {
-					ev_node.set_fulfilled_by_idx(idx, true);
-				} else {
-					ev_node.set_fulfilled_by_idx(
-						idx, 
-						last.read().as_ref().unwrap().upgrade().unwrap().status.load() == EventStatus::ACCEPTED
-					);
+	// Resolve dynamic events, and link txns held no more, in order. Returns if any txn was done with.
+	fn advance(&mut self) -> bool {
+		if self.txns.is_empty() {
+			return false
+		}
+		let before = self.txns.len();
+		// Pending states of txns before, still parked.
+		let mut blocked = HashSet::new();
+		let mut remaining = VecDeque::with_capacity(self.txns.len());
+		for tn in self.txns.drain(..) {
+			if tn.linked_at.load() == 0 {
+				let states = pending_states(&tn);
+				if !states.is_disjoint(&blocked) {
+					blocked.extend(states);
+					remaining.push_back(tn);
+					continue
 				}
-			});
-
-			if CONFIG.read().unwrap().monitor_enabled {
-				monitor::MONITOR.get().unwrap()[0].inc("evnode.let_occupy");
-				monitor::MONITOR.get().unwrap()[0].log(monitor::Metrics{
-					ts: utils::current_time_ns(),
-					content: format!("{},ready_to_be_fetched,{}", tn.txn_req_id, utils::current_time_ns()),
-				});
+				link(&tn);
 			}
+			resolve_dynamic_events(&tn);
+			if tn.status.load() == TxnStatus::WAITING && has_unresolved(&tn) {
+				blocked.extend(pending_states(&tn));
+				remaining.push_back(tn);
+			}
+		}
+		self.txns = remaining;
+		self.blocked = blocked;
+		self.txns.len() < before
+	}
 
-			// Has fulfilled according to detection.
-			if ev_node.no_waiting() {
-				// Try to fetch into queue.
-				match ev_node.status.compare_exchange(EventStatus::WAITING, EventStatus::INQUEUE) {
-					Ok(_) =>  {
-						monitor::MONITOR.get().unwrap()[0].inc("evnode.enqueue");
-						TPG.get().unwrap().ready_queue_in.send(ev_node.clone()).unwrap();
-					},
-					// Has been claimed by worker threads.
-					Err(state) => {
-						debug_assert!(state == EventStatus::CLAIMED);
-						monitor::MONITOR.get().unwrap()[0].inc("rare_condition.evnode.claimed_when_counting.");
-						ev_node.status.store(EventStatus::WAITING);
-					}
-				}
+	// Drop a txn never linked, aborted meanwhile. Linked ones leave by advance.
+	fn remove(&mut self, tn: &Arc<TxnNode>) {
+		self.txns.retain(|held| !Arc::ptr_eq(held, tn));
+	}
+
+	// On shutdown. Those never linked are done at once. Linked ones are left like those waiting in TPG.
+	fn abort_all(&mut self) {
+		for tn in self.txns.drain(..) {
+			let reason = ffi::AbortReason::new(ffi::ABORT_SHUTDOWN, "shutdown");
+			if tn.linked_at.load() == 0 {
+				tn.abort_unlinked(reason);
+				continue
 			}
-		});
-	}	
-	// Graceful Shutdown.
+			if let Some(cascade) = tn.abort(reason) {
+				monitor::record_abort(0, &tn, ffi::ABORT_SHUTDOWN, cascade);
+			}
+			// Could still wait on parents to commit.
+			std::mem::forget(tn);
+		}
+	}
+}
+
+// Abort txns not committed before their deadlines, as UDF asked. Cheap if none is due. Held ones are aborted too.
+fn abort_expired(deadlines: &mut BTreeMap<(u64, u64), Weak<TxnNode>>, parked: &mut Parked) {
+	let Some(((first, _), _)) = deadlines.first_key_value() else { return };
+	let now = utils::current_time_ns();
+	if *first > now {
+		return
+	}
+	while deadlines.first_key_value().is_some_and(|((deadline, _), _)| *deadline <= now) {
+		let tn = match deadlines.pop_first().unwrap().1.upgrade() {
+			Some(tn) => tn,
+			None => continue, // Committed and collected.
+		};
+		if tn.status.load() != TxnStatus::WAITING {
+			continue
+		}
+		if tn.linked_at.load() == 0 {
+			if tn.abort_unlinked(ffi::AbortReason::new(ffi::ABORT_TIMEOUT, "timed out")) {
+				monitor::MONITOR.get().unwrap()[0].inc("txn.timeout");
+				monitor::MONITOR.get().unwrap()[0].inc_abort(tn.type_idx, ffi::ABORT_TIMEOUT);
+				parked.remove(&tn);
+			}
+			continue
+		}
+		if let Some(cascade) = tn.abort(ffi::AbortReason::new(ffi::ABORT_TIMEOUT, "timed out")) {
+			monitor::MONITOR.get().unwrap()[0].inc("txn.timeout");
+			monitor::record_abort(0, &tn, ffi::ABORT_TIMEOUT, cascade);
+			tn.enqueue_sons_of_aborted(&TPG.get().unwrap().ready_queue_in);
+		}
+	}
+}
+
+// Init dependency of a linked event and put it into ready_queue if ready.
+fn enqueue_if_ready(tn: &TxnNode, ev_node: &Arc<EvNode>) {
+	ev_node.status.store(EventStatus::WAITING); // Possibly claimed during counting.
+	// Aborted by other events in this txn during construction.
+	if tn.status.load() == TxnStatus::ABORTED {
+		ev_node.abort();
+		return
+	}
+
+	ev_node.init_fulfilled();
+
+	if CONFIG.read().unwrap().monitor_enabled {
+		monitor::MONITOR.get().unwrap()[0].inc("evnode.let_occupy");
+		monitor::trace(0, tn, TraceStage::ReadyToBeFetched, None);
+	}
+
+	// Has fulfilled according to detection.
+	if ev_node.no_waiting() {
+		// Try to fetch into queue.
+		match ev_node.status.compare_exchange(EventStatus::WAITING, EventStatus::INQUEUE) {
+			Ok(_) =>  {
+				ev_node.mark_ready();
+				monitor::MONITOR.get().unwrap()[0].inc("evnode.enqueue");
+				TPG.get().unwrap().ready_queue_in.send(ev_node.clone()).unwrap();
+			},
+			// Has been claimed by worker threads, as parents accepted meanwhile. The worker owns it now.
+			Err(state) => {
+				debug_assert!(state == EventStatus::CLAIMED || state == EventStatus::INQUEUE);
+				monitor::MONITOR.get().unwrap()[0].inc("rare_condition.claimed_when_counting.");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_retry_among_queued() {
+		// (ts of VNF, or of the first attempt for a retry)
+		let taken_in = [100, 101, 100 /* retry of 100 */, 101, 102, 105, 101 /* retry of 101 */, 106];
+		let mut last_ts = 0;
+		let ts: Vec<u64> = taken_in.iter().map(|&ts| {
+			last_ts = ingress_ts(last_ts, ts);
+			last_ts
+		}).collect();
+		assert_eq!(ts, vec![100, 101, 102, 103, 104, 105, 106, 107]);
+	}
 }
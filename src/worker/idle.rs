@@ -0,0 +1,95 @@
+use std::sync::Mutex;
+use std::thread::{self, Thread};
+use std::time::Duration;
+use crossbeam::atomic::AtomicCell;
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::monitor::monitor;
+use crate::utils;
+
+/*
+	What threads do when there is nothing to do.
+	- Spin: keep polling. Lowest latency, burns the core.
+	- SpinThenYield: poll idle_spin_limit times, then yield the core on each poll.
+	- Park: poll idle_spin_limit times, then park till woken up on enqueue, or idle_park_timeout_us passed.
+		The timeout bounds the delay of a wakeup missed between the last poll and parking.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleStrategy {
+	Spin,
+	SpinThenYield,
+	Park,
+}
+
+// Threads parked. Checked by wake_all on every enqueue, so it should be cheap when nobody parks.
+static PARKED: Mutex<Vec<Thread>> = Mutex::new(Vec::new());
+static PARKED_CNT: AtomicCell<usize> = AtomicCell::new(0);
+
+// Wake up all parked threads. Called after enqueueing anything a thread could be waiting for.
+pub fn wake_all() {
+	if PARKED_CNT.load() == 0 {
+		return
+	}
+	PARKED.lock().unwrap().iter().for_each(|t| t.unpark());
+}
+
+// Idle state of one thread. Idle time is counted as thread.idle_us of the thread.
+pub struct Idler {
+	tid: usize,
+	strategy: IdleStrategy,
+	spin_limit: u32,
+	park_timeout: Duration,
+	spins: u32,
+	idle_since: Option<u64>,
+}
+
+impl Idler {
+	pub fn new(tid: usize) -> Self {
+		Idler {
+			tid,
+			strategy: CONFIG.read().unwrap().idle_strategy,
+			spin_limit: CONFIG.read().unwrap().idle_spin_limit,
+			park_timeout: Duration::from_micros(CONFIG.read().unwrap().idle_park_timeout_us),
+			spins: 0,
+			idle_since: None,
+		}
+	}
+
+	// Nothing to do this time.
+	pub fn idle(&mut self) {
+		if self.idle_since.is_none() {
+			self.idle_since = Some(utils::current_time_ns());
+		}
+		if self.spins < self.spin_limit || self.strategy == IdleStrategy::Spin {
+			self.spins = self.spins.saturating_add(1);
+			std::hint::spin_loop();
+			return
+		}
+		match self.strategy {
+			IdleStrategy::Spin => {},
+			IdleStrategy::SpinThenYield => thread::yield_now(),
+			IdleStrategy::Park => {
+				PARKED.lock().unwrap().push(thread::current());
+				PARKED_CNT.fetch_add(1);
+				thread::park_timeout(self.park_timeout);
+				PARKED_CNT.fetch_sub(1);
+				let mut parked = PARKED.lock().unwrap();
+				let me = thread::current().id();
+				if let Some(pos) = parked.iter().position(|t| t.id() == me) {
+					parked.swap_remove(pos);
+				}
+			},
+		}
+	}
+
+	// Got something to do. Ends idle.
+	pub fn busy(&mut self) {
+		self.spins = 0;
+		if let Some(since) = self.idle_since.take() {
+			monitor::MONITOR.get().unwrap()[self.tid]
+				.add("thread.idle_us", (utils::current_time_ns().saturating_sub(since) / 1000) as i64);
+		}
+	}
+}
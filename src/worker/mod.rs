@@ -1,2 +1,4 @@
 pub mod worker_threads;
-pub mod construct_thread;
\ No newline at end of file
+pub mod construct_thread;
+pub mod idle;
+pub mod watchdog;
\ No newline at end of file
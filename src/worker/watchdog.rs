@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crossbeam::atomic::AtomicCell;
+
+use crate::config::CONFIG;
+use crate::external::ffi;
+use crate::monitor::monitor;
+use crate::tpg::{ev_node::EventStatus, tpg::TPG, txn_node::{TxnNode, TxnStatus}};
+use crate::utils;
+use crate::worker::construct_thread::GRACEFUL_SHUTDOWN;
+
+// Stuck transactions found in the last round. Exported as a gauge.
+pub static STUCK_TXNS: AtomicCell<i64> = AtomicCell::new(0);
+
+// Longest blocking chain reported.
+const MAX_CHAIN: usize = 32;
+
+/*
+	Watchdog of transactions whose events never get ready, e.g. as a parent event was lost.
+	Every watchdog_interval_ms, transactions WAITING longer than watchdog_stuck_ms since linked are reported,
+	with the chain of unresolved dependencies blocking them. With watchdog_abort, they are aborted then,
+	with code ABORT_STUCK, releasing those waiting on them.
+ */
+pub fn watchdog_thread(tid: usize) {
+	let interval = CONFIG.read().unwrap().watchdog_interval_ms;
+	if interval == 0 {
+		return
+	}
+	let stuck_ns = CONFIG.read().unwrap().watchdog_stuck_ms * 1_000_000;
+	let abort = CONFIG.read().unwrap().watchdog_abort;
+	loop {
+		if unsafe { GRACEFUL_SHUTDOWN } {
+			println!("Watchdog shutdown. ");
+			break;
+		}
+		thread::sleep(Duration::from_millis(interval));
+
+		let now = utils::current_time_ns();
+		let stuck: Vec<_> = TPG.get().unwrap().live_txns().into_iter()
+			.filter(|tn| tn.status.load() == TxnStatus::WAITING)
+			.filter(|tn| tn.linked_at.load() != 0 && now.saturating_sub(tn.linked_at.load()) > stuck_ns)
+			.collect();
+		STUCK_TXNS.store(stuck.len() as i64);
+		for tn in stuck {
+			monitor::MONITOR.get().unwrap()[tid].inc("watchdog.stuck");
+			eprintln!("[WATCHDOG] txn {} waiting for {} ms: {}",
+				tn.txn_req_id, now.saturating_sub(tn.linked_at.load()) / 1_000_000, blocking_chain(&tn));
+			if abort {
+				if let Some(cascade) = tn.abort(ffi::AbortReason::new(ffi::ABORT_STUCK, "stuck")) {
+					monitor::MONITOR.get().unwrap()[tid].inc("watchdog.aborted");
+					monitor::record_abort(tid, &tn, ffi::ABORT_STUCK, cascade);
+					tn.enqueue_sons_of_aborted(&TPG.get().unwrap().ready_queue_in);
+				}
+			}
+		}
+	}
+}
+
+// Unresolved dependencies from the transaction, each on the next, till the one not waiting on others.
+fn blocking_chain(tn: &Arc<TxnNode>) -> String {
+	let mut chain = String::new();
+	let mut seen = HashSet::new();
+	let mut current = tn.clone();
+	for _ in 0..MAX_CHAIN {
+		if !seen.insert(Arc::as_ptr(&current)) {
+			chain += " (cycle, deadlock)";
+			return chain
+		}
+		match blocker(&current) {
+			(step, Some(next)) => {
+				chain += &step;
+				current = next;
+			},
+			(step, None) => {
+				chain += &step;
+				return chain
+			},
+		}
+	}
+	chain += " ...";
+	chain
+}
+
+// What the transaction waits on, and the transaction to follow if any.
+fn blocker(tn: &Arc<TxnNode>) -> (String, Option<Arc<TxnNode>>) {
+	let head = format!("txn {} {:?}", tn.txn_req_id, tn.status.load());
+	for e in tn.ev_nodes.read().unwrap().iter() {
+		match e.status.load() {
+			EventStatus::ACCEPTED | EventStatus::ABORTED => continue,
+			EventStatus::INQUEUE | EventStatus::CLAIMED => {
+				return (format!("{} ev {} {:?} (queued or running)", head, e.idx, e.status.load()), None)
+			},
+			EventStatus::CONSTRUCT if e.key_from.is_some() => continue, // Dynamic, never linked.
+			EventStatus::CONSTRUCT | EventStatus::WAITING => {},
+		}
+		for (idx, p) in e.read_from.iter().enumerate() {
+			if e.is_fulfilled(idx) {
+				continue
+			}
+			let parent = match p.try_read().and_then(|p| p.as_ref().and_then(|p| p.upgrade())) {
+				Some(parent) => parent,
+				None => return (format!("{} ev {} waits on [{}] of a parent released", head, e.idx, e.reads[idx]), None),
+			};
+			let ptn = parent.txn.upgrade().unwrap();
+			let step = format!("{} ev {} waits on [{}]{} <- ",
+				head, e.idx, e.reads[idx], if e.is_held(idx) { " till commit" } else { "" });
+			let parent_done = matches!(parent.status.load(), EventStatus::ACCEPTED | EventStatus::ABORTED);
+			if parent_done && !e.is_held(idx) {
+				return (format!("{}txn {} ev {} {:?} (done, wakeup lost)", step, ptn.txn_req_id, parent.idx, parent.status.load()), None)
+			}
+			return (step, Some(ptn))
+		}
+		if let Some(merge_from) = e.merge_from.try_read() {
+			if let Some((parent, _)) = merge_from.iter().find(|(_, fulfilled)| !fulfilled.load()) {
+				if let Some(ptn) = parent.upgrade().and_then(|p| p.txn.upgrade()) {
+					return (format!("{} ev {} waits on merge <- ", head, e.idx), Some(ptn))
+				}
+			}
+		}
+		if e.status.load() == EventStatus::WAITING && e.no_waiting() {
+			return (format!("{} ev {} ready but never queued", head, e.idx), None)
+		}
+	}
+	// All events done. Commit waits on parents.
+	let parents: Vec<Arc<TxnNode>> = tn.read_from.read().unwrap().iter().flatten()
+		.chain(tn.cover.read().unwrap().values().flatten())
+		.filter(|p| p.status.load() != TxnStatus::COMMITED)
+		.cloned().collect();
+	match parents.into_iter().min_by_key(|p| p.ts) {
+		Some(p) => (format!("{} commit waits on <- ", head), Some(p)),
+		None => (format!("{} (nothing pending, commit never triggered)", head), None),
+	}
+}
@@ -1,128 +1,126 @@
-use crate::{config::CONFIG, database::{api::Database, simpledb::DB}, monitor::monitor, tpg::{ev_node::{EvNode, EventStatus}, tpg::TPG}, utils, worker::construct_thread::GRACEFUL_SHUTDOWN};
-use std::{mem, sync::Arc};
+use crate::{config::CONFIG, database::{api::Database, simpledb::DB}, monitor::{monitor::{self, Record, TraceStage}, latency::{self, Stage}}, tpg::{ev_node::{EvNode, EventStatus}, ready_queue::{ReadyReceiver, WorkerSink}, tpg::TPG}, utils, worker::{construct_thread::GRACEFUL_SHUTDOWN, idle::Idler}};
+use std::sync::Arc;
+use std::sync::mpsc::TryRecvError;
+use crossbeam::deque::Worker;
 
 // These worker threads traverse through TPG and execute the operations.
 /*
 	Worker Threads fetches events from multiple places to decrease race condition:
 	- Events that had just ready by last accepted events.
-	- Events from Local queue.
-	- Events from Global queue.
+	- Events from Local queue. Sons made ready by this thread go here, other than the next one.
+	- Events from Global queue. Fetched in batch into local queue.
+	- Events stolen from local queues of other threads.
  */
 pub fn execute_thread(tid: usize){
 	utils::report_cpu_core(format!("Executor {}", tid).as_str());
 
 	let lq_cap = CONFIG.read().unwrap().max_event_batch;
-	let mut local_queue = Vec::<Option<Arc<EvNode>>>::with_capacity(
-		lq_cap	
-	);
-	(0..lq_cap).into_iter().for_each(|_| local_queue.push(None));
-	let mut local_queue_size = 0;
+	let worker_idx = tid - CONFIG.read().unwrap().vnf_threads_num as usize - 1;
+	// Open to steal from other threads.
+	let local_queue = TPG.get().unwrap().take_local_queue(worker_idx);
+	let sink = WorkerSink { worker: worker_idx, local: &local_queue };
 
 	let mut exit = false;
+	let mut idler = Idler::new(tid);
 
-	let mut evn_option_next : Option<Arc<EvNode>> = None; 
+	let mut evn_option_next : Option<Arc<EvNode>> = None; // Claimed by this thread already.
 	let mut evn_option : Option<Arc<EvNode>>; // Option: if we have migrated from related one, we don't need to fetch from queue.
+	let mut claimed: bool;
 	// Event disposal loop.
 	loop {
 		// Use just ready first.
 		evn_option = evn_option_next;
 		evn_option_next = None;
+		claimed = evn_option.is_some();
 
 		if evn_option.is_none() {
-			// Use local queue first.
-			if local_queue_size != 0 {
-				evn_option = mem::take(&mut local_queue[local_queue_size - 1]);
-				debug_assert!(evn_option.is_some());
-				local_queue_size -= 1;
-			} else {
-				// Use global queue then.
-				let ev_gd = TPG.get().unwrap().ready_queue_out.try_lock();
-				if ev_gd.is_err() {
-					continue
-				}
-				loop {
+			// Use local queue first. Then those queued to this thread by others.
+			evn_option = local_queue.pop().or_else(|| TPG.get().unwrap().pop_inbox(worker_idx));
+		}
+		if evn_option.is_none() {
+			// Use global queue then.
+			if let Ok(mut ev_gd) = TPG.get().unwrap().ready_queue_out.try_lock() {
+				let before = local_queue.len();
+				let fetched = fetch_global(&mut ev_gd, &local_queue, lq_cap, |ev| {
+					monitor::trace(tid, &ev.txn.upgrade().unwrap(), TraceStage::EvClaimed, Some(ev.idx));
+					if CONFIG.read().unwrap().verbose {
+						println!("[DEBUG] Evnode claimed by thread {}", tid);
+					}
+				});
+				match fetched {
 					// Local queue full. Continue to execute.
-					if local_queue_size == lq_cap { 
-						if CONFIG.read().unwrap().monitor_enabled {
-							monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-								ts: utils::current_time_ns(),
-								content: format!("{},thread local queue size,{},{}", tid, utils::current_time_ns(), local_queue_size),
-							});
+					Ok(()) => {},
+					// Global queue empty. Dispose those in local queue or continue waiting. Listen from graceful shutdown.
+					Err(TryRecvError::Empty) => {
+						if unsafe { GRACEFUL_SHUTDOWN } {
+							exit = true;
+							println!("Executor thread {} shutdown. ", tid);
 						}
-						break 
-					}
-					match ev_gd.as_ref().unwrap().try_recv() {
-						Ok(ev) => {
-							if CONFIG.read().unwrap().monitor_enabled {
-								monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-									ts: utils::current_time_ns(),
-									content: format!("{},ev_claimed,{},{}", ev.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), ev.idx),
-								});
-							}
-							local_queue[local_queue_size] = Some(ev);
-							local_queue_size += 1;
-							if CONFIG.read().unwrap().verbose {
-								println!("[DEBUG] Evnode claimed by thread {}, thread queue size: {}", tid, local_queue_size);
-							}
-						},
-						Err(err) => {
-							match err {
-								// Loop to get from the ready queue. Listen from graceful shutdown.
-								std::sync::mpsc::TryRecvError::Empty => {
-									if unsafe { GRACEFUL_SHUTDOWN } == true{
-										exit = true;
-										println!("Executor thread {} shutdown. ", tid);
-									}
-									// Global queue empty. Dispose those in local queue or continue waiting.
-									break
-								},
-								_ => {
-									println!("Channel paniced. Worker {} exit.", tid);
-								},
-							}
-						},
-					}
+					},
+					Err(TryRecvError::Disconnected) => {
+						println!("Channel paniced. Worker {} exit.", tid);
+					},
+				}
+				// Size after each fetch from the global queue taking any.
+				if local_queue.len() > before && CONFIG.read().unwrap().monitor_enabled {
+					monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
+						ts: utils::current_time_ns(),
+						record: Record::LocalQueueSize { tid, at: utils::current_time_ns(), len: local_queue.len() },
+					});
 				}
 			}
 			if exit { break } // Disposal ends.
-			if evn_option.is_none() { continue } // Global queue also empty. Go back to query.
+			evn_option = local_queue.pop();
 		}
+		if evn_option.is_none() {
+			// Steal from other threads at last.
+			evn_option = TPG.get().unwrap().steal(worker_idx);
+			if evn_option.is_none() { // All empty. Go back to query.
+				idler.idle();
+				continue
+			}
+			monitor::MONITOR.get().unwrap()[tid].inc("evnode.stolen");
+		}
+		idler.busy();
 
 		let evn = evn_option.as_ref().unwrap();
 
-		match evn.status.load() {
-			EventStatus::INQUEUE => {
-				debug_assert!(evn.ready());
-				evn.status.store(EventStatus::CLAIMED); // Claimed by this worker thread.
-			},
-			EventStatus::CONSTRUCT => panic!("bug."),
-			// When in queue, it's resetted and set WAITING.
-			EventStatus::WAITING => {
-				println!("Worker Queue: Rare condition. Reset event in queue.");
-				debug_assert!({
-					evn.no_waiting() == false
-				});
-				continue;
-			},
-			EventStatus::CLAIMED | EventStatus::ACCEPTED | EventStatus::ABORTED => {
-				println!("Worker Queue: Rare condition. claimed event being disposed again.");
-				continue;
-			},
+		if !claimed {
+			match evn.status.load() {
+				EventStatus::INQUEUE => {
+					// Claimed by this worker thread.
+					if evn.status.compare_exchange(EventStatus::INQUEUE, EventStatus::CLAIMED).is_err() {
+						monitor::MONITOR.get().unwrap()[tid].inc("race.claim_lost");
+						continue;
+					}
+				},
+				EventStatus::CONSTRUCT => panic!("bug."),
+				// When in queue, it's resetted and set WAITING. Enqueued again once ready.
+				EventStatus::WAITING => {
+					monitor::MONITOR.get().unwrap()[tid].inc("race.reset_in_queue");
+					continue;
+				},
+				// Enqueued more than once. The other copy is disposed.
+				EventStatus::CLAIMED | EventStatus::ACCEPTED | EventStatus::ABORTED => {
+					monitor::MONITOR.get().unwrap()[tid].inc("race.duplicate_in_queue");
+					continue;
+				},
+			}
 		}
+		let tn = evn.txn.upgrade().unwrap();
+		let type_idx = tn.type_idx;
+		latency::record(type_idx, Stage::ReadyToClaim, utils::current_time_ns().saturating_sub(evn.ready_at.load()));
+		// Inputs read after this are valid till the epoch changes.
+		let epoch = evn.epoch.load();
 		// Fetch required states;
 		debug_assert!(
 			evn.read_from.len() 
 			== evn.reads.len()
 		);
 
-		if CONFIG.read().unwrap().monitor_enabled {
-			monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},fetching_value,{},{}", evn.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), evn.idx),
-			});
-		}
+		monitor::trace(tid, &tn, TraceStage::FetchingValue, Some(evn.idx));
 
-		let values = evn.reads
+		let values: Vec<Vec<u8>> = evn.reads
 			.iter().enumerate().map(
 				|(idx, r)| {
 					if (*evn.read_from[idx].read())
@@ -142,6 +140,10 @@ pub fn execute_thread(tid: usize){
 							}
 							true
 						});
+						// Sent by the parent on another partition.
+						if let Some(value) = evn.take_forwarded(idx) {
+							return value
+						}
 						let ts = (*evn.read_from[idx].read()).as_ref().unwrap().upgrade().unwrap()
 							.txn.upgrade().unwrap()
 							.ts;
@@ -151,28 +153,25 @@ pub fn execute_thread(tid: usize){
 				}
 			).collect();
 
-		if CONFIG.read().unwrap().monitor_enabled {
-			monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},fetch_value_done,{},{}", evn.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), evn.idx),
-			});
-		}
+		monitor::trace(tid, &tn, TraceStage::FetchValueDone, Some(evn.idx));
 
-		// Call the Cpp execution func.
+		// Call the Cpp execution func. One result for each state written.
+		let exec_start = utils::current_time_ns();
 		let (abortion, v) = evn.execute(&values, values.len() as i32);
+		latency::record(type_idx, Stage::Execution, utils::current_time_ns().saturating_sub(exec_start));
 
-		if CONFIG.read().unwrap().monitor_enabled {
-			monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-				ts: utils::current_time_ns(),
-				content: format!("{},execution_done,{},{}", evn.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), evn.idx),
-			});
-		}
+		monitor::trace(tid, &tn, TraceStage::ExecutionDone, Some(evn.idx));
 
 		/*
 			Re-check the status here again. Abortion could have happended between last check and now.
 			Swap out, check it, and put it back.
+			Inputs are stale if the epoch changed. Parents were reset, and the result is discarded.
 		 */
-		if !abortion {
+		if evn.epoch.load() != epoch || evn.status.load() == EventStatus::WAITING {
+			discard_stale(tid, evn);
+			continue;
+		}
+		let Some(reason) = abortion else {
 			match evn.status.compare_exchange(EventStatus::CLAIMED, EventStatus::ACCEPTED){
 				Ok(_) => {} // No abortion happens. Just go on execution.
 				Err(original) => {
@@ -180,37 +179,78 @@ pub fn execute_thread(tid: usize){
 						original == EventStatus::ABORTED // Has just been aborted during execution.
 						|| original == EventStatus::WAITING // Parent has been aborted during execution.
 					); 
+					if original == EventStatus::WAITING {
+						discard_stale(tid, evn);
+					}
 					continue; // Find another to go on.
 				}
 			}
 			evn.notify_txn_accept();
+			monitor::trace(tid, &tn, TraceStage::Accept, Some(evn.idx));
 			if CONFIG.read().unwrap().monitor_enabled {
-				monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-					ts: utils::current_time_ns(),
-					content: format!("{},accept,{},{}", evn.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), evn.idx),
-				});
 				monitor::MONITOR.get().unwrap()[tid].inc("evnode.accept");
 			}
-			evn.write_back(&v, DB.get().unwrap());
-			evn_option_next = evn.get_next_option_push_others_ready(&TPG.get().unwrap().ready_queue_in);
-			continue;
-		} else {
-			if CONFIG.read().unwrap().monitor_enabled {
-				monitor::MONITOR.get().unwrap()[tid].log(monitor::Metrics{
-					ts: utils::current_time_ns(),
-					content: format!("{},abort,{},{}", evn.txn.upgrade().unwrap().txn_req_id, utils::current_time_ns(), evn.idx),
-				});
-				monitor::MONITOR.get().unwrap()[tid].inc("evnode.abort");
+			evn.write_back(&v, tn.ts, DB.get().unwrap());
+			if TPG.get().unwrap().partitioned {
+				let cnt = evn.forward(&v, worker_idx, &*TPG.get().unwrap().policy);
+				monitor::MONITOR.get().unwrap()[tid].add("evnode.forwarded", cnt as i64);
 			}
-			evn.notify_txn_abort(); // All these nodes are aborted and reverted back.
-			(*evn.txn.upgrade().unwrap().ev_nodes.read())
-				.iter().for_each(|aborted_evn|{
-					debug_assert!(aborted_evn.status.load() == EventStatus::ABORTED);
-					let n = aborted_evn.get_next_option_push_others_ready(&TPG.get().unwrap().ready_queue_in);
-					if n.is_some(){
-						local_queue.push(n);
-					}
-				})
+			evn_option_next = evn.get_next_option_push_others_ready(&sink);
+			continue;
+		};
+		monitor::trace(tid, &tn, TraceStage::Abort, Some(evn.idx));
+		if CONFIG.read().unwrap().monitor_enabled {
+			monitor::MONITOR.get().unwrap()[tid].inc("evnode.abort");
 		}
+		let code = reason.code;
+		// All these nodes are aborted and reverted back.
+		if let Some(cascade) = evn.notify_txn_abort(reason) {
+			monitor::record_abort(tid, &evn.txn.upgrade().unwrap(), code, cascade);
+		}
+		evn.txn.upgrade().unwrap().enqueue_sons_of_aborted(&sink);
+	}
+}
+
+// Result computed on inputs reset by parents is dropped. Reschedule if parents accepted again already, otherwise they would.
+fn discard_stale(tid: usize, evn: &Arc<EvNode>) {
+	monitor::MONITOR.get().unwrap()[tid].inc("race.stale_result");
+	if evn.ready() && evn.status.compare_exchange(EventStatus::WAITING, EventStatus::INQUEUE).is_ok() {
+		monitor::MONITOR.get().unwrap()[tid].inc("race.rescheduled");
+		evn.mark_ready();
+		TPG.get().unwrap().ready_queue_in.send(evn.clone()).unwrap();
+	}
+}
+
+/*
+	Move ready events from the global queue into the local queue, in the order served, till it holds cap.
+	Ok once full, or the error of the global queue once it has none.
+ */
+fn fetch_global<T>(global: &mut ReadyReceiver<T>, local: &Worker<T>, cap: usize, mut on_fetch: impl FnMut(&T)) -> Result<(), TryRecvError> {
+	while local.len() < cap {
+		let ev = global.try_recv()?;
+		on_fetch(&ev);
+		local.push(ev);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tpg::ready_queue::with_levels;
+
+	#[test]
+	fn test_fetch_by_priority() {
+		let (tx, mut rx) = with_levels(3, 0);
+		let local = Worker::new_fifo();
+		// (priority, item)
+		[(0, 1), (2, 2), (1, 3), (2, 4), (0, 5)].iter().for_each(|&(p, item)| tx.send_by_priority(p, item).unwrap());
+
+		// A batch of 3, then the rest.
+		assert_eq!(fetch_global(&mut rx, &local, 3, |_| {}), Ok(()));
+		let mut order: Vec<u32> = std::iter::from_fn(|| local.pop()).collect();
+		assert_eq!(fetch_global(&mut rx, &local, 3, |_| {}), Err(TryRecvError::Empty));
+		order.extend(std::iter::from_fn(|| local.pop()));
+		assert_eq!(order, vec![2, 4, 3, 1, 5]);
 	}
-}
\ No newline at end of file
+}